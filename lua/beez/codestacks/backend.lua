@@ -37,7 +37,8 @@ local backend = res
 
 ---@class Beez.codestacks.backend
 ---@field init_tracing fun(path: string, level: string): boolean
----@field setup fun(project: string, base_dir: string, recent_files_limit: integer): boolean
+---@field setup fun(project: string, base_dir: string, recent_files_limit: integer, opts?: Beez.codestacks.backend.opts): boolean
+---@field flush fun(): boolean
 ---@field add_stack fun(name: string): boolean
 ---@field is_active_stack fun(name: string): boolean
 ---@field list_stacks fun(): Beez.codestacks.Stack[]
@@ -46,23 +47,205 @@ local backend = res
 ---@field set_active_stack fun(name: string): boolean
 ---@field get_active_stack fun(): Beez.codestacks.Stack?
 ---@field get_stack fun(name?: string): Beez.codestacks.Stack?
+---@field snapshot_stack fun(name?: string): string?
+---@field list_snapshots fun(name?: string): string[]
+---@field diff_stacks fun(a: string, b: string): Beez.codestacks.StackDiff?
+---@field dedupe_paths fun(): Beez.codestacks.DedupeReport
+---@field rename_path fun(old: string, new: string): Beez.codestacks.RenameReport
+---@field find_moved_path fun(path: string, root?: string): Beez.codestacks.MoveCandidate[]
+---@field gc fun(mode?: "dry_run"|"prune"|"archive"): Beez.codestacks.GcReport?
+---@field check_integrity fun(repair?: boolean): Beez.codestacks.Issue[]
+---@field list_templates fun(): table<string, Beez.codestacks.StackTemplate>
+---@field create_stack_from_template fun(template: string, vars?: table<string, string>): Beez.codestacks.Stack?
 ---@field add_recent_file fun(path: string): boolean
 ---@field remove_recent_file fun(path: string): boolean
 ---@field list_recent_files fun(): string[]
 ---@field save_recent_files fun(): boolean
----@field pin_buffer fun(path: string, label: string): boolean
+---@field pin_buffer fun(path: string, label?: string, policy?: Beez.codestacks.ConflictPolicy): Beez.codestacks.PinOutcome?
 ---@field unpin_buffer fun(path: string): boolean
+---@field pin_temp_buffer fun(path: string, ttl?: integer): Beez.codestacks.PinOutcome?
+---@field clear_temp_pins fun(): Beez.codestacks.PinnedBuffer[]
+---@field next_free_label fun(labels?: string[]): string?
+---@field is_valid_label fun(label: string): boolean
+---@field insert_pin_at fun(path: string, label: string?, index: integer, policy?: Beez.codestacks.ConflictPolicy): Beez.codestacks.PinOutcome?
+---@field move_pin fun(path: string, index: integer): boolean
+---@field swap_pins fun(a: string, b: string): boolean
+---@field update_pin_cursor fun(path: string, lnum: integer, col: integer): boolean
+---@field set_pin_list fun(name: string): boolean
+---@field list_pin_lists fun(): Beez.codestacks.PinList[]
+---@field remove_pin_list fun(name: string): boolean
 ---@field list_pinned_buffers fun(): Beez.codestacks.PinnedBuffer[]
 ---@field enable_recent_files fun(enable: boolean): boolean
 ---@field get_pinned_buffer fun(path: string): Beez.codestacks.PinnedBuffer?
----@field add_global_mark fun(path: string, desc: string, line: string, lineno: integer): boolean
+---@field get_target fun(path: string): Beez.codestacks.Target
+---@field add_global_mark fun(path: string, desc: string, line: string, lineno: integer, opts?: Beez.codestacks.MarkOpts): boolean
 ---@field remove_global_mark fun(path: string, lineno: integer): boolean
----@field list_global_marks fun(path?: string): Beez.codestacks.GlobalMark[]
+---@field list_global_marks fun(path?: string, filter?: Beez.codestacks.MarkFilter): Beez.codestacks.GlobalMark[]
 ---@field list_all_global_marks fun(): Beez.codestacks.GlobalMark[]
----@field update_global_mark fun(path: string, lineno: integer, new_lineno?: integer): boolean
----@field add_local_mark fun(path: string, line: string, lineno: integer): boolean
+---@field set_mark_key fun(path: string, lineno: integer, key?: string): boolean Keys are single letters A-Z
+---@field get_mark_by_key fun(key: string): Beez.codestacks.GlobalMark?
+---@field search fun(query: string, all_projects?: boolean, limit?: integer): Beez.codestacks.SearchHit[]
+---@field update_global_mark fun(path: string, lineno: integer, new_lineno?: integer, new_desc?: string, opts?: Beez.codestacks.MarkOpts): boolean
+---@field add_local_mark fun(path: string, lineno: integer, line: string, opts?: Beez.codestacks.MarkOpts): boolean
 ---@field remove_local_mark fun(path: string, lineno: integer): boolean
----@field list_local_marks fun(path?: string): Beez.codestacks.LocalMark[]
----@field update_local_mark fun(path: string, lineno: integer, new_lineno?: integer): boolean
+---@field list_local_marks fun(path?: string, filter?: Beez.codestacks.MarkFilter): Beez.codestacks.LocalMark[]
+---@field visit_local_mark fun(path: string, lineno: integer): boolean
+---@field list_evicted_marks fun(name?: string): Beez.codestacks.LocalMark[]
+---@field update_local_mark fun(path: string, lineno: integer, new_lineno?: integer, opts?: Beez.codestacks.MarkOpts): boolean
+---@field locate_mark fun(id: string, contents?: string): Beez.codestacks.Relocation?
+---@field validate_marks fun(all_stacks?: boolean): Beez.codestacks.MarkStatus[]
+---@field next_mark fun(path: string, lineno: integer, kind?: "local"|"global"): Beez.codestacks.MarkPos?
+---@field prev_mark fun(path: string, lineno: integer, kind?: "local"|"global"): Beez.codestacks.MarkPos?
+---@field export_quickfix fun(kinds?: ("global"|"local"|"pins")[]): Beez.codestacks.QfItem[]
+---@field export_errorformat fun(kinds?: ("global"|"local"|"pins")[]): string
+---@field import_quickfix fun(items: Beez.codestacks.QfItem[], kind: "local"|"global"): integer
+---@field import_errorformat fun(text: string, kind: "local"|"global"): integer
+---@field has_marks fun(path: string): boolean
+---@field relocate_marks fun(path: string, old_contents: string?, new_contents: string): Beez.codestacks.RelocationReport
+---@field shift_marks fun(path: string, first: integer, last: integer, new_last: integer): boolean
+---@field discard_mark_shifts fun(path: string): boolean
+---@field get_notes fun(name?: string): Beez.codestacks.StackNotes?
+---@field set_notes fun(content: string, name?: string): boolean
+---@field append_notes fun(content: string, name?: string): boolean
+---@field heartbeat fun(): boolean
+---@field pause_tracking fun(): boolean
+---@field time_report fun(group_by: "day"|"week"|"stack", name?: string, utc_offset?: integer|fun(time: integer): integer): Beez.codestacks.TimeEntry[]
+
+---@class Beez.codestacks.backend.opts
+---@field idle_timeout? integer
+---@field labels? string[]
+---@field conflict_policy? Beez.codestacks.ConflictPolicy
+---@field temp_labels? string[]
+---@field temp_pin_ttl? integer
+---@field clear_temp_on_switch? boolean
+---@field resolve_symlinks? boolean
+---@field relocation_confidence? number
+
+---@class Beez.codestacks.DedupeReport
+---@field rewritten integer Paths that changed when normalized
+---@field pins integer Duplicate pins removed
+---@field local_marks integer Duplicate local marks removed
+---@field global_marks integer Duplicate global marks removed
+---@field recent_files integer Duplicate recent files removed
+
+---@class Beez.codestacks.RenameReport
+---@field pins integer
+---@field local_marks integer
+---@field global_marks integer
+---@field recent_files integer
+
+---@class Beez.codestacks.MoveCandidate
+---@field path string
+---@field matched integer Marked lines found in the file
+---@field total integer Marked lines searched for
+---@field score number Share of marked lines found, 0 when matched on file name only
+
+---@class Beez.codestacks.DanglingRef
+---@field stack string
+---@field kind "pin"|"local_mark"|"global_mark"|"note"
+---@field path string
+---@field label? string Label of a pin
+---@field pin_list? string Pin list of a pin
+---@field lineno? integer Line of a mark
+
+---@class Beez.codestacks.GcReport
+---@field refs Beez.codestacks.DanglingRef[]
+---@field recent_files string[]
+---@field pruned boolean Whether the references were removed
+---@field archive? string File the removed references were written to
+
+---@class Beez.codestacks.Issue
+---@field project? string Nil for files shared by all projects
+---@field stack? string
+---@field check string Check that failed, ie parse_error, duplicate_mark, mark_stack, duplicate_label, evicted_mark_ref
+---@field message string
+---@field repaired boolean
+
+---@class Beez.codestacks.Relocation
+---@field lineno integer Line the mark most likely moved to
+---@field confidence number Score of the line between 0 and 1
+---@field ambiguous boolean Whether another line scored about as well
+
+---@class Beez.codestacks.MarkChange
+---@field id string
+---@field kind "local"|"global"
+---@field stack string
+---@field path string
+---@field from integer Line of the mark before the change
+---@field to? integer Line the mark moved to, or the best candidate of an ambiguous mark
+---@field confidence number
+
+---@class Beez.codestacks.MarkPos
+---@field kind "local"|"global"
+---@field id string
+---@field path string
+---@field lineno integer
+---@field col? integer
+
+---@class Beez.codestacks.QfItem
+---@field filename string
+---@field lnum integer
+---@field col integer 1-based, 0 when unknown
+---@field end_lnum? integer
+---@field end_col? integer
+---@field text string
+
+---@class Beez.codestacks.MarkStatus
+---@field id string
+---@field kind "local"|"global"
+---@field stack string
+---@field path string
+---@field lineno integer Line of the mark on disk
+---@field line string Text the mark was set on
+---@field desc? string Description of a global mark
+---@field status "ok"|"file_missing"|"line_out_of_range"|"text_changed"|"relocated"
+---@field current_line? string Text now at the marked line
+---@field candidate? Beez.codestacks.Relocation Where the marked line most likely moved to
+
+---@class Beez.codestacks.RelocationReport
+---@field moved Beez.codestacks.MarkChange[]
+---@field deleted Beez.codestacks.MarkChange[]
+---@field ambiguous Beez.codestacks.MarkChange[]
+
+---@class Beez.codestacks.MarkOpts
+---@field col? integer 0-based column the mark starts at
+---@field end_lineno? integer Last line of the marked range
+---@field end_col? integer 0-based column the range ends at
+---@field key? string Register to jump to a global mark with, a single letter A-Z
+---@field note? string Multi-line note, an empty note clears it
+---@field tags? string[]
+---@field priority? integer
+
+---@class Beez.codestacks.SearchHit
+---@field project string
+---@field stack string
+---@field kind "pin"|"local_mark"|"global_mark"|"note"
+---@field id string Mark id, the path of a pin or the stack of notes
+---@field path string
+---@field lineno? integer
+---@field field "desc"|"line"|"note"|"tags"|"path" Field that matched best
+---@field text string Text of the field that matched best
+---@field score number Higher is a better match
+
+---@class Beez.codestacks.MarkFilter
+---@field tags? string[] Tags a mark must all have
+---@field min_priority? integer
+---@field has_note? boolean
+
+---@alias Beez.codestacks.TargetKind "file"|"directory"|"uri"|"terminal"|"remote"
+
+---@class Beez.codestacks.Target
+---@field kind Beez.codestacks.TargetKind
+---@field path string Path normalized according to its kind
+---@field exists? boolean Nil when existence cannot be checked locally, ie for remote paths
+
+---@alias Beez.codestacks.ConflictPolicy "reject"|"replace"|"swap"
+
+---@class Beez.codestacks.PinOutcome
+---@field status "pinned"|"relabeled"|"unchanged"|"rejected"|"replaced"|"swapped"|"invalid_label"|"no_free_label"
+---@field path string
+---@field label? string
+---@field evicted? string Path of the buffer that was unpinned to free the label
+---@field swapped? Beez.codestacks.PinnedBuffer Pinned buffer that was given a different label
 
 return backend
@@ -21,7 +21,16 @@ local hl = require("beez.codestacks.highlights")
 ---@field ui_pin_sep_hl? string Highlight group for pinened buffer list separator
 ---@field recent_labels? string[] List of characters to use for recent buffers
 ---@field temp_labels? string[] List of characters to use for temporary pinned buffers that can be cleared with keymap
+---@field temp_pin_ttl? integer Seconds after which temporary pins expire, never when nil
+---@field clear_temp_pins_on_switch? boolean Clear temporary pins of a stack when switching to another stack
+---@field pin_labels? string[] List of characters that can be used as pin labels, in order of preference
+---@field pin_conflict_policy? "reject"|"replace"|"swap" What to do when pinning with a label that is already used
 ---@field recent_files_limit? integer Maximum number of recent files to store
+---@field resolve_symlinks? boolean Resolve symlinks when normalizing paths of pins, marks and recent files
+---@field mark_relocation_confidence? number Minimum confidence between 0 and 1 to move a mark after its line changed
+---@field max_local_marks? integer Most local marks kept per stack, the least recently visited are evicted first. Unbounded when nil
+---@field max_local_marks_per_file? integer Most local marks kept per file in a stack. Unbounded when nil
+---@field idle_timeout? integer Seconds of inactivity after which time tracking for a stack stops
 
 ---@type Beez.codestacks.config
 M.def_config = {
@@ -48,7 +57,16 @@ M.def_config = {
 
   recent_labels = { ";", "/", ".", "," },
   temp_labels = { "1", "2", "3", "4", "5", "6", "7", "8", "9" },
+  temp_pin_ttl = nil,
+  clear_temp_pins_on_switch = false,
+  resolve_symlinks = false,
+  mark_relocation_confidence = 0.7,
+  max_local_marks = nil,
+  max_local_marks_per_file = nil,
+  pin_labels = nil,
+  pin_conflict_policy = "replace",
   recent_files_limit = 100,
+  idle_timeout = 300,
 }
 
 function M.setup(opts)
@@ -5,7 +5,7 @@ local hl = require("beez.codestacks.highlights")
 local tabline = require("beez.codestacks.tabline")
 local u = require("beez.u")
 local debug_tabline = false
-local H = { init_tabline = false }
+local H = { init_tabline = false, pre_write = {}, attached = {} }
 local M = {
   autocmd_group = "autocmd.Beez.codestacks.buflist",
   def_hooks = {},
@@ -15,19 +15,68 @@ local M = {
   recentfiles = {},
   global_marks = {},
   local_marks = {},
+  marks = {},
+  notes = {},
+  paths = {},
+  quickfix = {},
+  timetracking = {},
   ui = {},
 }
 
 ---@class Beez.codestacks.PinnedBuffer
 ---@field path string
 ---@field label string
+---@field kind Beez.codestacks.TargetKind
+---@field position? integer 1-based position of the pin in the stack
+---@field lnum? integer Last known cursor line
+---@field col? integer Last known cursor column
+---@field temp boolean Whether this is a temporary pin
+---@field expires_at? integer Unix time after which a temporary pin is removed
 
 ---@class Beez.codestacks.Stack
 ---@field name string
----@field pinned_buffers Beez.codestacks.PinnedBuffer[]
+---@field pinned_buffers Beez.codestacks.PinnedBuffer[] Pins of the visible pin list
+---@field pin_list string Name of the visible pin list
 ---@field global_marks Beez.codestacks.GlobalMark[]
 ---@field local_marks Beez.codestacks.LocalMark[]
 
+---@class Beez.codestacks.PinList
+---@field name string
+---@field active boolean
+---@field count integer
+
+---@class Beez.codestacks.Changes<T>: {added: T[], removed: T[], changed: {old: T, new: T}[]}
+
+---@class Beez.codestacks.StackDiff
+---@field pins Beez.codestacks.Changes<Beez.codestacks.PinnedBuffer>
+---@field local_marks Beez.codestacks.Changes<Beez.codestacks.LocalMark>
+---@field global_marks Beez.codestacks.Changes<Beez.codestacks.GlobalMark>
+
+---@class Beez.codestacks.StackTemplate
+---@field desc string
+---@field stack string
+---@field pins string[]
+
+---@class Beez.codestacks.NoteRef
+---@field id string
+---@field found boolean
+---@field kind? "local"|"global"
+---@field stack? string
+---@field path? string
+---@field lineno? integer
+---@field line? string
+---@field desc? string
+
+---@class Beez.codestacks.StackNotes
+---@field stack string
+---@field content string
+---@field refs Beez.codestacks.NoteRef[]
+
+---@class Beez.codestacks.TimeEntry
+---@field period string
+---@field stack string
+---@field seconds integer
+
 --- Gets or initialized popup window
 ---@return NuiPopup
 local function get_popup()
@@ -62,6 +111,38 @@ local function call_backend(...)
   return ok, error
 end
 
+--- Returns the name of a buffer in the form the backend stores paths, ie terminals without
+--- their pid and files with symlinks resolved when enabled
+---@param bufnr? integer Defaults to the current buffer
+---@return string
+local function buf_target(bufnr)
+  local name = vim.api.nvim_buf_get_name(bufnr or 0)
+  if name == "" then
+    return name
+  end
+  local ok, target = call_backend(be.get_target, name)
+  if not ok then
+    return name
+  end
+  return target.path
+end
+
+--- Opens a path stored by the backend, switching to a loaded buffer with that path so
+--- terminals are not started again
+---@param path string
+local function open_target(path)
+  if buf_target() == path then
+    return
+  end
+  for _, bufnr in ipairs(vim.api.nvim_list_bufs()) do
+    if vim.api.nvim_buf_is_loaded(bufnr) and buf_target(bufnr) == path then
+      vim.api.nvim_set_current_buf(bufnr)
+      return
+    end
+  end
+  vim.cmd.edit(path)
+end
+
 --- Setup autocmds for handling buffers
 local function setup_autocmds()
   local group = vim.api.nvim_create_augroup(M.autocmd_group, { clear = true })
@@ -104,11 +185,114 @@ local function setup_autocmds()
     end,
   })
 
+  -- Keep marks on their lines while editing
+  vim.api.nvim_create_autocmd("BufEnter", {
+    group = group,
+    callback = function(event)
+      M.local_marks.track(event.buf)
+    end,
+  })
+
+  -- Marks of buffers closed without writing go back to where they are on disk
+  vim.api.nvim_create_autocmd("BufUnload", {
+    group = group,
+    callback = function(event)
+      if vim.bo[event.buf].modified and event.match ~= "" then
+        call_backend(be.discard_mark_shifts, event.match)
+      end
+    end,
+  })
+
+  -- Remember file contents before a write to diff marks against, only files with marks are read
+  vim.api.nvim_create_autocmd("BufWritePre", {
+    pattern = "*",
+    callback = function(event)
+      local ok, has_marks = call_backend(be.has_marks, event.match)
+      if not ok or not has_marks then
+        return
+      end
+      local f = io.open(event.match, "r")
+      if f ~= nil then
+        H.pre_write[event.match] = f:read("*a")
+        f:close()
+      end
+    end,
+  })
+
   -- Autocmd to check for outdated marks when a file is written
   vim.api.nvim_create_autocmd("BufWritePost", {
     pattern = "*",
     callback = function(event)
-      M.global_marks.check_for_outdated(event.match)
+      local old_contents = H.pre_write[event.match]
+      H.pre_write[event.match] = nil
+      M.global_marks.check_for_outdated(event.match, old_contents)
+    end,
+  })
+
+  -- Jumps from the quickfix window to a local mark count as visits so the mark is evicted last
+  vim.api.nvim_create_autocmd("FileType", {
+    group = group,
+    pattern = "qf",
+    callback = function(event)
+      if vim.fn.getwininfo(vim.fn.win_getid())[1].loclist == 1 then
+        return
+      end
+      vim.keymap.set("n", "<CR>", function()
+        M.quickfix.jump("cc", vim.fn.line("."))
+      end, { buffer = event.buf, desc = "Jump to entry and visit its local mark" })
+    end,
+  })
+
+  -- Keep time tracking alive while there is activity
+  vim.api.nvim_create_autocmd({ "BufEnter", "CursorHold", "CursorHoldI", "FocusGained", "BufWritePost" }, {
+    group = group,
+    callback = function()
+      call_backend(be.heartbeat)
+    end,
+  })
+
+  -- Stop time tracking when leaving neovim
+  vim.api.nvim_create_autocmd({ "FocusLost", "VimLeavePre" }, {
+    group = group,
+    callback = function()
+      call_backend(be.pause_tracking)
+    end,
+  })
+
+  -- Remember the cursor position of pinned buffers
+  vim.api.nvim_create_autocmd("BufLeave", {
+    group = group,
+    callback = function(event)
+      local path = vim.api.nvim_buf_get_name(event.buf)
+      if path == "" then
+        return
+      end
+      local pos = vim.api.nvim_win_get_cursor(0)
+      call_backend(be.update_pin_cursor, path, pos[1], pos[2])
+    end,
+  })
+
+  -- Save cursor positions that are only kept in memory
+  vim.api.nvim_create_autocmd("VimLeavePre", {
+    group = group,
+    callback = function()
+      call_backend(be.flush)
+    end,
+  })
+
+  -- Keep pins and marks pointing at files moved with oil
+  vim.api.nvim_create_autocmd("User", {
+    group = group,
+    pattern = "OilActionsPost",
+    callback = function(event)
+      if event.data == nil or event.data.err ~= nil then
+        return
+      end
+      for _, action in ipairs(event.data.actions or {}) do
+        if action.type == "move" then
+          M.paths.rename((action.src_url:gsub("^oil://", "")), (action.dest_url:gsub("^oil://", "")))
+        end
+      end
     end,
   })
 
@@ -135,14 +319,15 @@ function M.def_hooks.default_hook_buf_is_valid(bufnr)
   return vim.api.nvim_buf_is_valid(bufnr) and vim.fn.buflisted(bufnr) == 1
 end
 
---- Default hook for hook_label_id_valid. Allow only lowercase letters and numbers 1-9 as labels.
+--- Default hook for hook_label_id_valid. Allow recent labels and the pin labels known to the backend.
 ---@param label string
 ---@return boolean
 function M.def_hooks.is_valid_label(label)
-  if label:match("^[a-zA-Z1-9" .. table.concat(c.config.recent_labels, "") .. "]$") == nil then
-    return false
+  if vim.tbl_contains(c.config.recent_labels, label) then
+    return true
   end
-  return true
+  local ok, valid = call_backend(be.is_valid_label, label)
+  return ok and valid
 end
 
 --- Default hook for hook_buf_name. Use the buffers basename, otherwise add the parent directories to the name.
@@ -196,7 +381,7 @@ end
 ---@return string, string[][]
 function M.def_hooks.pinned_buf_name(b, i, bufs, unique_names)
   local basename = u.paths.basename(b.path)
-  if b.path:startswith("oil://") then
+  if b.kind == "directory" or (b.kind == "uri" and b.path:startswith("oil://")) then
     basename = u.paths.sep .. basename
   end
   return M.def_hooks.buf_name({
@@ -239,6 +424,18 @@ function M.def_hooks.buf_list(bufs)
     end
     table.insert(display_bufs, display_buf)
   end
+
+  -- Followed by pinned buffers in order of their position
+  local pinned = M.pinned.list()
+  for i, p in ipairs(pinned) do
+    local name, hls = get_pinned_name(p, i, pinned, unique_names)
+    unique_names[name] = true
+    table.insert(display_bufs, {
+      name = hls,
+      pinned = true,
+      label = { p.label, c.config.ui_pin_label_hl },
+    })
+  end
   return display_bufs
 end
 
@@ -280,7 +477,18 @@ function M.setup(config)
 
   local base_path = debug.getinfo(1).source:match("@?(.*/)")
   call_backend(be.init_tracing, vim.fs.joinpath(base_path, "logs", "codestacks.log"), "info")
-  call_backend(be.setup, M.session, c.config.data_dir, c.config.recent_files_limit)
+  call_backend(be.setup, M.session, c.config.data_dir, c.config.recent_files_limit, {
+    idle_timeout = c.config.idle_timeout,
+    labels = c.config.pin_labels,
+    conflict_policy = c.config.pin_conflict_policy,
+    temp_labels = c.config.temp_labels,
+    temp_pin_ttl = c.config.temp_pin_ttl,
+    clear_temp_on_switch = c.config.clear_temp_pins_on_switch,
+    resolve_symlinks = c.config.resolve_symlinks,
+    relocation_confidence = c.config.mark_relocation_confidence,
+    max_local_marks = c.config.max_local_marks,
+    max_local_marks_per_file = c.config.max_local_marks_per_file,
+  })
   setup_autocmds()
   hl.init()
 
@@ -303,6 +511,161 @@ function M.stacks.add()
   end)
 end
 
+--- Saves a snapshot of a stack, defaults to the active stack
+---@param name? string
+---@return string? id
+function M.stacks.snapshot(name)
+  local ok, id = call_backend(be.snapshot_stack, name)
+  if ok and id ~= nil then
+    vim.notify("Saved snapshot: " .. id, vim.log.levels.INFO)
+  end
+  return id
+end
+
+--- Normalizes the paths of all pins, marks and recent files and merges duplicates
+---@return Beez.codestacks.DedupeReport?
+function M.stacks.dedupe_paths()
+  local ok, report = call_backend(be.dedupe_paths)
+  if not ok then
+    return nil
+  end
+  vim.notify(
+    string.format(
+      "Rewrote %d paths, removed %d pins, %d local marks, %d global marks and %d recent files",
+      report.rewritten,
+      report.pins,
+      report.local_marks,
+      report.global_marks,
+      report.recent_files
+    ),
+    vim.log.levels.INFO
+  )
+  return report
+end
+
+--- Validates the data of all projects, optionally repairing what can be repaired
+---@param repair? boolean
+---@return Beez.codestacks.Issue[]
+function M.stacks.check_integrity(repair)
+  local ok, issues = call_backend(be.check_integrity, repair)
+  if not ok then
+    return {}
+  end
+  if #issues == 0 then
+    vim.notify("No issues found", vim.log.levels.INFO)
+  end
+  for _, issue in ipairs(issues) do
+    local level = issue.repaired and vim.log.levels.INFO or vim.log.levels.WARN
+    local prefix = issue.repaired and "Repaired: " or ""
+    vim.notify(prefix .. issue.message, level)
+  end
+  if repair then
+    M.ui.refresh()
+  end
+  return issues
+end
+
+--- Points all pins, marks and recent files for old, or files inside it, to new
+---@param old string
+---@param new string
+---@return Beez.codestacks.RenameReport?
+function M.paths.rename(old, new)
+  local ok, report = call_backend(be.rename_path, old, new)
+  if not ok then
+    return nil
+  end
+  M.ui.refresh()
+  return report
+end
+
+--- Finds likely new locations of a missing file by looking for the lines marked in it
+---@param path string
+---@param root? string Directory to search, defaults to cwd
+---@return Beez.codestacks.MoveCandidate[]
+function M.paths.find_moved(path, root)
+  local ok, candidates = call_backend(be.find_moved_path, path, root)
+  if not ok then
+    return {}
+  end
+  return candidates
+end
+
+--- Finds pins, marks and recent files of files that no longer exist
+---@param mode? "dry_run"|"prune"|"archive" Defaults to dry_run, archive writes removed references to data_dir
+---@return Beez.codestacks.GcReport?
+function M.paths.gc(mode)
+  local ok, report = call_backend(be.gc, mode)
+  if not ok or report == nil then
+    return nil
+  end
+  local action = report.pruned and "Removed" or "Found"
+  vim.notify(
+    string.format("%s %d dangling pins and marks and %d recent files", action, #report.refs, #report.recent_files),
+    vim.log.levels.INFO
+  )
+  if report.pruned then
+    M.ui.refresh()
+  end
+  return report
+end
+
+--- Returns snapshot ids, optionally only for a single stack
+---@param name? string
+---@return string[]
+function M.stacks.list_snapshots(name)
+  local ok, ids = call_backend(be.list_snapshots, name)
+  if not ok then
+    return {}
+  end
+  return ids
+end
+
+--- Compares two stacks, each can be a stack name, snapshot id or path to a stack json file
+---@param a string
+---@param b string
+---@return Beez.codestacks.StackDiff?
+function M.stacks.diff(a, b)
+  local ok, diff = call_backend(be.diff_stacks, a, b)
+  if not ok then
+    return nil
+  end
+  return diff
+end
+
+--- Creates a new stack from a template stored in data_dir/templates.json
+---@param template string
+---@param vars? table<string, string> Values for placeholders, {root} defaults to cwd
+function M.stacks.add_from_template(template, vars)
+  vars = vim.tbl_extend("keep", vars or {}, { root = vim.fn.getcwd() })
+  if vars.name == nil then
+    vim.ui.input({ prompt = "Give your new stack a name: " }, function(res)
+      if res == nil then
+        return
+      end
+      vars.name = res
+      M.stacks.add_from_template(template, vars)
+    end)
+    return
+  end
+
+  local ok, _ = call_backend(be.create_stack_from_template, template, vars)
+  if ok then
+    vim.schedule(function()
+      M.ui.refresh()
+    end)
+  end
+end
+
+--- Returns all stack templates keyed by name
+---@return table<string, Beez.codestacks.StackTemplate>
+function M.stacks.list_templates()
+  local ok, templates = call_backend(be.list_templates)
+  if not ok then
+    return {}
+  end
+  return templates
+end
+
 --- Removes a stack by name
 ---@param name string
 ---@return Beez.codestacks.Stack?
@@ -378,6 +741,111 @@ function M.stacks.get(name)
   return stack
 end
 
+--- Jumps to a mark returned by the backend
+---@param pos? Beez.codestacks.MarkPos
+---@return boolean
+local function jump_to_mark(pos)
+  if pos == nil then
+    return false
+  end
+  open_target(pos.path)
+  local lnum = math.min(pos.lineno, vim.api.nvim_buf_line_count(0))
+  pcall(vim.api.nvim_win_set_cursor, 0, { lnum, pos.col or 0 })
+  if pos.kind == "local" then
+    call_backend(be.visit_local_mark, pos.path, pos.lineno)
+  end
+  return true
+end
+
+--- Jumps to the next mark of the active stack in file and line order, wrapping around
+---@param kind? "local"|"global" Only walk these marks
+---@return boolean
+function M.marks.next(kind)
+  local path = vim.api.nvim_buf_get_name(0)
+  local lineno = vim.api.nvim_win_get_cursor(0)[1]
+  local _, pos = call_backend(be.next_mark, path, lineno, kind)
+  return jump_to_mark(pos)
+end
+
+--- Jumps to the previous mark of the active stack in file and line order, wrapping around
+---@param kind? "local"|"global" Only walk these marks
+---@return boolean
+function M.marks.prev(kind)
+  local path = vim.api.nvim_buf_get_name(0)
+  local lineno = vim.api.nvim_win_get_cursor(0)[1]
+  local _, pos = call_backend(be.prev_mark, path, lineno, kind)
+  return jump_to_mark(pos)
+end
+
+--- Sends marks and pins of the active stack to the quickfix list
+---@param kinds? ("global"|"local"|"pins")[] What to send, everything by default
+function M.quickfix.send(kinds)
+  local ok, items = call_backend(be.export_quickfix, kinds)
+  if not ok then
+    return
+  end
+  vim.fn.setqflist({}, " ", { title = "codestacks: " .. M.stacks.get_active(), items = items })
+end
+
+--- Runs a quickfix jump and records a visit when it lands on a local mark
+---@param cmd? "cc"|"cnext"|"cprev"|"cfirst"|"clast" Defaults to cc
+---@param nr? integer Entry to jump to with cc, or count for the others
+function M.quickfix.jump(cmd, nr)
+  local ok = pcall(vim.cmd, { cmd = cmd or "cc", count = nr })
+  if not ok then
+    return
+  end
+  local path = vim.api.nvim_buf_get_name(0)
+  local lineno = vim.api.nvim_win_get_cursor(0)[1]
+  call_backend(be.visit_local_mark, path, lineno)
+end
+
+--- Adds the entries of the quickfix list to the active stack as marks
+---@param kind? "local"|"global" Defaults to global
+---@return integer Number of entries imported
+function M.quickfix.import(kind)
+  local items = {}
+  for _, item in ipairs(vim.fn.getqflist()) do
+    if item.valid == 1 and item.bufnr > 0 then
+      item.filename = vim.api.nvim_buf_get_name(item.bufnr)
+      table.insert(items, item)
+    end
+  end
+  local _, count = call_backend(be.import_quickfix, items, kind or "global")
+  vim.notify("Imported " .. (count or 0) .. " quickfix entries", vim.log.levels.INFO)
+  return count or 0
+end
+
+--- Compares marks with their files on disk, ie to pick broken marks to fix
+---@param opts? {all_stacks?: boolean, broken_only?: boolean}
+---@return Beez.codestacks.MarkStatus[]
+function M.stacks.validate_marks(opts)
+  opts = opts or {}
+  local ok, statuses = call_backend(be.validate_marks, opts.all_stacks)
+  if not ok then
+    return {}
+  end
+  if opts.broken_only then
+    return vim.tbl_filter(function(s)
+      return s.status ~= "ok"
+    end, statuses)
+  end
+  return statuses
+end
+
+--- Fuzzy searches mark descriptions, line text, notes, tags and pinned paths of all stacks
+---@param query string Whitespace separated terms that all have to match
+---@param opts? {all_projects?: boolean, limit?: integer}
+---@return Beez.codestacks.SearchHit[]
+function M.stacks.search(query, opts)
+  opts = opts or {}
+  local ok, hits = call_backend(be.search, query, opts.all_projects, opts.limit)
+  if not ok then
+    return {}
+  end
+  return hits
+end
+
 --- Gets the current active stack name
 ---@return string
 function M.stacks.get_active()
@@ -386,7 +854,7 @@ function M.stacks.get_active()
 end
 
 --- Pins the current buffer with label
----@param opts? {label?: string, temp?: boolean}
+---@param opts? {label?: string, temp?: boolean, ttl?: integer, policy?: Beez.codestacks.ConflictPolicy}
 function M.pinned.pin(opts)
   opts = opts or {}
   local ok, active_stack = call_backend(be.get_active_stack)
@@ -403,39 +871,62 @@ function M.pinned.pin(opts)
     end)
   end
 
-  local label = opts.label
-  -- Use the next temp label if temp is true
+  local filename = vim.api.nvim_buf_get_name(0)
+  if filename == "" then
+    return
+  end
+
+  -- Temporary pins get the next free temp label
   if opts.temp == true then
-    local pinned = M.pinned.list()
-    local pinned_label_map = {}
-    for _, p in ipairs(pinned) do
-      pinned_label_map[p.label] = true
+    local ok, outcome = call_backend(be.pin_temp_buffer, filename, opts.ttl)
+    if not ok or outcome == nil then
+      return
     end
-
-    -- Find first available temp label
-    for _, l in ipairs(c.config.temp_labels) do
-      if not pinned_label_map[l] then
-        label = l
-        break
-      end
+    if outcome.status == "no_free_label" then
+      vim.notify("No free temp labels left...", vim.log.levels.WARN)
+      return
     end
-  else
-    if label == nil then
-      label = opts.label or vim.fn.nr2char(vim.fn.getchar())
-      local hook_is_valid_label = c.config.hook_label_is_valid or M.def_hooks.is_valid_label
-      if not hook_is_valid_label(label) then
-        return
-      end
+    vim.notify("Pinned buffer with temp label: " .. outcome.label, vim.log.levels.INFO)
+    M.ui.refresh()
+    return
+  end
+
+  local label = opts.label
+  if label == nil then
+    label = vim.fn.nr2char(vim.fn.getchar())
+    local hook_is_valid_label = c.config.hook_label_is_valid or M.def_hooks.is_valid_label
+    if not hook_is_valid_label(label) then
+      return
     end
   end
 
-  local filename = vim.api.nvim_buf_get_name(0)
-  if filename == "" then
+  local ok, outcome = call_backend(be.pin_buffer, filename, label, opts.policy)
+  if not ok or outcome == nil then
     return
   end
-  local ok, _ = call_backend(be.pin_buffer, filename, label)
+  if outcome.status == "rejected" then
+    vim.notify("Label " .. label .. " is already used by another buffer...", vim.log.levels.WARN)
+  elseif outcome.status == "invalid_label" then
+    vim.notify("Invalid label: " .. label, vim.log.levels.WARN)
+  elseif outcome.status == "no_free_label" then
+    vim.notify("No free labels left...", vim.log.levels.WARN)
+  elseif outcome.status == "swapped" then
+    vim.notify(
+      "Pinned buffer with label: " .. outcome.label .. ", moved other buffer to " .. outcome.swapped.label,
+      vim.log.levels.INFO
+    )
+    M.ui.refresh()
+  else
+    vim.notify("Pinned buffer with label: " .. outcome.label, vim.log.levels.INFO)
+    M.ui.refresh()
+  end
+end
+
+--- Removes all temporary pins from the current stack
+function M.pinned.clear_temp()
+  local ok, removed = call_backend(be.clear_temp_pins)
   if ok then
-    vim.notify("Pinned buffer with label: " .. label, vim.log.levels.INFO)
+    vim.notify("Cleared " .. #removed .. " temporary pins...", vim.log.levels.INFO)
     M.ui.refresh()
   end
 end
@@ -445,21 +936,9 @@ end
 function M.pinned.unpin(path)
   path = path or vim.api.nvim_buf_get_name(0)
   local pinned_buf = M.pinned.get(path)
+  -- Backend takes care of compacting the remaining temp labels
   local ok, _ = call_backend(be.unpin_buffer, path)
   if ok then
-    if pinned_buf ~= nil and pinned_buf.label:match("^[1-9]$") then
-      -- If label temp label, fix order of the rest of the temp labels
-      local temp_pinned_bufs = M.pinned.list({ temp = true })
-      -- Sort buffers by label
-      table.sort(temp_pinned_bufs, function(a, b)
-        return a.label < b.label
-      end)
-      for i, t in ipairs(temp_pinned_bufs) do
-        if t.label > pinned_buf.label then
-          ok, _ = call_backend(be.pin_buffer, t.path, tostring(i))
-        end
-      end
-    end
     if pinned_buf ~= nil then
       vim.notify("Unpinned buffer with label: " .. pinned_buf.label, vim.log.levels.INFO)
     else
@@ -469,6 +948,79 @@ function M.pinned.unpin(path)
   end
 end
 
+--- Pins the current buffer at a position, shifting later pins back
+---@param index integer 1-based position
+---@param label? string Defaults to the next free label
+---@param path? string
+function M.pinned.insert_at(index, label, path)
+  path = path or vim.api.nvim_buf_get_name(0)
+  if path == "" then
+    return
+  end
+  local ok, _ = call_backend(be.insert_pin_at, path, label, index)
+  if ok then
+    M.ui.refresh()
+  end
+end
+
+--- Moves a pinned buffer to a new position
+---@param index integer 1-based position
+---@param path? string
+function M.pinned.move(index, path)
+  path = path or vim.api.nvim_buf_get_name(0)
+  local ok, _ = call_backend(be.move_pin, path, index)
+  if ok then
+    M.ui.refresh()
+  end
+end
+
+--- Swaps the positions of two pinned buffers
+---@param a string
+---@param b string
+function M.pinned.swap(a, b)
+  local ok, _ = call_backend(be.swap_pins, a, b)
+  if ok then
+    M.ui.refresh()
+  end
+end
+
+--- Switches the visible pin list of the current stack, creating it if needed
+---@param name? string Prompts for a name if not given
+function M.pinned.set_list(name)
+  if name == nil then
+    vim.ui.input({ prompt = "Pin list name: " }, function(res)
+      if res == nil or res == "" then
+        return
+      end
+      M.pinned.set_list(res)
+    end)
+    return
+  end
+  local ok, switched = call_backend(be.set_pin_list, name)
+  if ok and switched then
+    vim.notify("Switched to pin list: " .. name, vim.log.levels.INFO)
+    vim.schedule(function()
+      M.ui.refresh()
+    end)
+  end
+end
+
+--- Returns the pin lists of the current stack, the visible one first
+---@return Beez.codestacks.PinList[]
+function M.pinned.lists()
+  local ok, lists = call_backend(be.list_pin_lists)
+  if not ok then
+    return {}
+  end
+  return lists
+end
+
+--- Removes a pin list that is not currently visible
+---@param name string
+function M.pinned.remove_list(name)
+  call_backend(be.remove_pin_list, name)
+end
+
 --- Returns a list of pinned buffers for current stack
 ---@param opts? {temp?: boolean, not_temp?: boolean}
 ---@return Beez.codestacks.PinnedBuffer[]
@@ -478,25 +1030,24 @@ function M.pinned.list(opts)
   if not ok then
     return {}
   end
-
-  local buffers = {}
-  local temp_labels_map = {}
-  for _, l in ipairs(c.config.temp_labels) do
-    temp_labels_map[l] = true
+  -- Pinned buffers are returned in order of their position
+  for i, p in ipairs(pinned_buffers) do
+    p.position = i
   end
 
-  -- Return pinned buffers with temp labels only
+  local buffers = {}
+  -- Return temporary pinned buffers only
   if opts.temp == true then
     for _, p in ipairs(pinned_buffers) do
-      if temp_labels_map[p.label] then
+      if p.temp then
         table.insert(buffers, p)
       end
     end
     return buffers
-  -- Return pinned buffers without temp labels
+  -- Return pinned buffers that are not temporary
   elseif opts.not_temp == true then
     for _, p in ipairs(pinned_buffers) do
-      if not temp_labels_map[p.label] then
+      if not p.temp then
         table.insert(buffers, p)
       end
     end
@@ -513,7 +1064,7 @@ function M.pinned.show(opts)
   get_popup()
   local NuiLine = require("nui.line")
   local vtns = vim.api.nvim_create_namespace("Beez.codestacks.float_vt_ns")
-  local curr_filename = vim.api.nvim_buf_get_name(0)
+  local curr_filename = buf_target()
 
   local i = 1
   local vts = {}
@@ -540,45 +1091,10 @@ function M.pinned.show(opts)
     pinned_map[tostring(l)] = p
   end
 
-  for _, l in ipairs({
-    "1",
-    "2",
-    "3",
-    "4",
-    "5",
-    "6",
-    "7",
-    "8",
-    "9",
-    " ",
-    "a",
-    "b",
-    "c",
-    "d",
-    "e",
-    "f",
-    "g",
-    "h",
-    "i",
-    "j",
-    "k",
-    "l",
-    "m",
-    "n",
-    "o",
-    "p",
-    "q",
-    "r",
-    "s",
-    "t",
-    "u",
-    "v",
-    "w",
-    "x",
-    "y",
-    "z",
-  }) do
-    local p = pinned_map[l]
+  --- Renders the row of a label with the pin or mark under it
+  ---@param l string
+  ---@param p? {path: string}
+  local function render_entry(l, p)
     local line = NuiLine()
     local v = { l }
     if p ~= nil then
@@ -602,6 +1118,18 @@ function M.pinned.show(opts)
     render_line(line)
     table.insert(vts, v)
   end
+
+  local number_labels = { "1", "2", "3", "4", "5", "6", "7", "8", "9" }
+  for _, l in ipairs(number_labels) do
+    render_entry(l, pinned_map[l])
+  end
+  render_entry(" ", nil)
+  -- Other pins follow in their stored order, so moving and swapping them shows here
+  for _, p in ipairs(pinned) do
+    if not vim.tbl_contains(number_labels, p.label) then
+      render_entry(p.label, p)
+    end
+  end
   -- for _, p in ipairs(pinned) do
   --   local line = NuiLine()
   --   line:append("  ")
@@ -750,7 +1278,12 @@ function M.pinned.pick(label)
       end
     end
     if pinned_buf ~= nil then
-      vim.cmd.edit(pinned_buf.path)
+      open_target(pinned_buf.path)
+      -- Restore the last cursor position for this stack
+      if pinned_buf.lnum ~= nil then
+        local lnum = math.min(pinned_buf.lnum, vim.api.nvim_buf_line_count(0))
+        pcall(vim.api.nvim_win_set_cursor, 0, { lnum, pinned_buf.col or 0 })
+      end
       return true
     end
   end
@@ -806,23 +1339,95 @@ function M.ui.get_tabline()
   return tabline.get(M.stacks.get_active() or "", bufs)
 end
 
---- Add a new global mark
+--- Returns the notes of a stack with resolved mark references, defaults to the active stack
+---@param name? string
+---@return Beez.codestacks.StackNotes?
+function M.notes.get(name)
+  local ok, notes = call_backend(be.get_notes, name)
+  if not ok then
+    return nil
+  end
+  return notes
+end
+
+--- Replaces the notes of a stack, defaults to the active stack
+---@param content string
+---@param name? string
+---@return boolean
+function M.notes.set(content, name)
+  local ok, res = call_backend(be.set_notes, content, name)
+  return ok and res
+end
+
+--- Appends to the notes of a stack, defaults to the active stack
+---@param content string
+---@param name? string
+---@return boolean
+function M.notes.append(content, name)
+  local ok, res = call_backend(be.append_notes, content, name)
+  return ok and res
+end
+
+--- Returns the text used to reference a mark from notes
+---@param mark Beez.codestacks.GlobalMark|Beez.codestacks.LocalMark
+---@return string
+function M.notes.mark_ref(mark)
+  return "[[mark:" .. mark.id .. "]]"
+end
+
+--- Returns time spent on stacks, grouped by day, week or stack
+---@param group_by? "day"|"week"|"stack"
+---@param name? string Only report on this stack
+---@return Beez.codestacks.TimeEntry[]
+function M.timetracking.report(group_by, name)
+  -- Group days by the local time of each interval, which changes with daylight saving time
+  local function utc_offset(time)
+    local l, u = os.date("*t", time), os.date("!*t", time)
+    -- The UTC table has isdst false, which os.time would read as standard time
+    u.isdst = l.isdst
+    return os.time(l) - os.time(u)
+  end
+  local _, entries = call_backend(be.time_report, group_by or "day", name, utc_offset)
+  return entries or {}
+end
+
+--- Returns the start line of the visual selection or cursor and the column and range to mark
+---@return integer, Beez.codestacks.MarkOpts
+local function mark_range()
+  local cursor = vim.api.nvim_win_get_cursor(0)
+  local mode = vim.fn.mode()
+  if not mode:match("^[vV\22]") then
+    return cursor[1], { col = cursor[2] }
+  end
+  local from, to = vim.fn.getpos("v"), vim.fn.getpos(".")
+  if from[2] > to[2] or (from[2] == to[2] and from[3] > to[3]) then
+    from, to = to, from
+  end
+  vim.api.nvim_feedkeys(vim.keycode("<Esc>"), "nx", false)
+  -- Linewise selections cover whole lines
+  if mode == "V" then
+    return from[2], { end_lineno = to[2] }
+  end
+  return from[2], { col = from[3] - 1, end_lineno = to[2], end_col = to[3] - 1 }
+end
+
+--- Add a new global mark, covering the selection in visual mode
 ---@param path? string
 function M.global_marks.add(path)
+  local lineno, opts = mark_range()
+  local line = vim.api.nvim_buf_get_lines(0, lineno - 1, lineno, false)[1] or ""
   vim.ui.input({ prompt = "Describe the mark: " }, function(res)
     if res == nil then
       return
     end
 
     path = path or vim.api.nvim_buf_get_name(0)
-    local pos = vim.api.nvim_win_get_cursor(0)
-    local line = vim.api.nvim_get_current_line()
-    call_backend(be.add_global_mark, path, res, line, pos[1])
+    call_backend(be.add_global_mark, path, res, line, lineno, opts)
   end)
 end
 
 --- Returns a list of global marks
----@param opts? {all?: boolean, path?: string}
+---@param opts? {all?: boolean, path?: string, filter?: Beez.codestacks.MarkFilter}
 ---@return Beez.codestacks.GlobalMark[]
 function M.global_marks.list(opts)
   opts = opts or {}
@@ -830,16 +1435,49 @@ function M.global_marks.list(opts)
     local _, gmarks = call_backend(be.list_all_global_marks)
     return gmarks
   end
-  local _, gmarks = call_backend(be.list_global_marks, opts.path)
+  local _, gmarks = call_backend(be.list_global_marks, opts.path, opts.filter)
   return gmarks
 end
 
---- Update a global mark
+--- Update a global mark, an empty note clears the note
 ---@param path string
 ---@param lineno integer
----@param updates {desc?: string, lineno?: integer}
+---@param updates {desc?: string, lineno?: integer, note?: string, tags?: string[], priority?: integer}
 function M.global_marks.update(path, lineno, updates)
-  call_backend(be.update_global_mark, path, lineno, updates.lineno, updates.desc)
+  call_backend(be.update_global_mark, path, lineno, updates.lineno, updates.desc, {
+    note = updates.note,
+    tags = updates.tags,
+    priority = updates.priority,
+  })
+end
+
+--- Sets the key of the global mark on the current line, or clears it without a key
+---@param key? string A single letter A-Z
+function M.global_marks.set_key(key)
+  if key ~= nil and not key:match("^[A-Z]$") then
+    vim.notify("Mark keys must be a single letter A-Z, got " .. key, vim.log.levels.WARN)
+    return
+  end
+  local path = vim.api.nvim_buf_get_name(0)
+  local lineno = vim.api.nvim_win_get_cursor(0)[1]
+  local _, ok = call_backend(be.set_mark_key, path, lineno, key)
+  if not ok then
+    vim.notify("No global mark on this line to set key " .. (key or "") .. " on", vim.log.levels.WARN)
+  end
+end
+
+--- Jumps to the global mark with the given key in the active stack
+---@param key string
+---@return boolean
+function M.global_marks.jump(key)
+  local _, gmark = call_backend(be.get_mark_by_key, key)
+  if gmark == nil then
+    return false
+  end
+  open_target(gmark.path)
+  local lnum = math.min(gmark.lineno, vim.api.nvim_buf_line_count(0))
+  pcall(vim.api.nvim_win_set_cursor, 0, { lnum, gmark.col or 0 })
+  return true
 end
 
 --- Delete a global mark
@@ -851,64 +1489,140 @@ end
 
 --- Add a new local mark
 function M.local_marks.add()
-  local line = vim.api.nvim_get_current_line()
+  local lineno, opts = mark_range()
+  local line = vim.api.nvim_buf_get_lines(0, lineno - 1, lineno, false)[1] or ""
   local path = vim.api.nvim_buf_get_name(0)
-  local pos = vim.api.nvim_win_get_cursor(0)
-  call_backend(be.add_local_mark, path, pos[1], line)
+  call_backend(be.add_local_mark, path, lineno, line, opts)
+end
+
+--- Shifts marks of a buffer in the backend as lines are added or removed
+---@param bufnr integer
+function M.local_marks.track(bufnr)
+  if H.attached[bufnr] or vim.bo[bufnr].buftype ~= "" then
+    return
+  end
+  local path = vim.api.nvim_buf_get_name(bufnr)
+  if path == "" then
+    return
+  end
+  H.attached[bufnr] = vim.api.nvim_buf_attach(bufnr, false, {
+    on_lines = function(_, _, _, first, last, new_last)
+      if not H.attached[bufnr] then
+        return true
+      end
+      if last ~= new_last then
+        call_backend(be.shift_marks, path, first, last, new_last)
+      end
+    end,
+    on_detach = function()
+      H.attached[bufnr] = nil
+    end,
+    -- Reloading drops unwritten edits
+    on_reload = function()
+      call_backend(be.discard_mark_shifts, path)
+    end,
+  })
 end
 
 --- Returns a list of local marks
+---@param opts? {path?: string, filter?: Beez.codestacks.MarkFilter}
 ---@return Beez.codestacks.LocalMark[]
 function M.local_marks.list(opts)
   opts = opts or {}
-  local _, lmarks = call_backend(be.list_local_marks, opts.path)
+  local _, lmarks = call_backend(be.list_local_marks, opts.path, opts.filter)
   return lmarks
 end
 
---- Delete a local mark
+--- Update a local mark, an empty note clears the note
 ---@param path string
 ---@param lineno integer
-function M.local_marks.remove(path, lineno)
-  call_backend(be.remove_local_mark, path, lineno)
+---@param updates {lineno?: integer, note?: string, tags?: string[], priority?: integer}
+function M.local_marks.update(path, lineno, updates)
+  call_backend(be.update_local_mark, path, lineno, updates.lineno, {
+    note = updates.note,
+    tags = updates.tags,
+    priority = updates.priority,
+  })
 end
 
---- Checks whether a mark needs to be updated after a save
+--- Records that a local mark was jumped to, so it is evicted last when over the limits
 ---@param path string
 ---@param lineno integer
----@param old_line string
----@param save fun(integer)
-local function check_for_outdated_marks(path, lineno, old_line, save)
-  local line = u.os.read_line_at(path, lineno)
-  -- If the line has changed, update the mark
-  if line ~= old_line then
-    -- Look for the new line number
-    local new_lineno = vim.fn.search(old_line, "n")
-    new_lineno = new_lineno or vim.fn.search(old_line, "nb")
-    if new_lineno > 0 and new_lineno ~= lineno then
-      save(new_lineno)
+function M.local_marks.visit(path, lineno)
+  call_backend(be.visit_local_mark, path, lineno)
+end
+
+--- Picks a local mark of the active stack to jump to
+function M.local_marks.pick()
+  local lmarks = M.local_marks.list() or {}
+  vim.ui.select(lmarks, {
+    prompt = "Local marks",
+    format_item = function(m)
+      return string.format("%s:%d %s", u.paths.basename(m.path), m.lineno, vim.trim(m.line))
+    end,
+  }, function(m)
+    if m ~= nil then
+      jump_to_mark({ kind = "local", id = m.id, path = m.path, lineno = m.lineno, col = m.col })
     end
+  end)
+end
+
+--- Returns the local marks evicted to stay within the limits, most recently evicted first
+---@param name? string Stack name, the active stack when nil
+---@return Beez.codestacks.LocalMark[]
+function M.local_marks.list_evicted(name)
+  local ok, lmarks = call_backend(be.list_evicted_marks, name)
+  if not ok then
+    return {}
   end
+  return lmarks
 end
 
---- Checks current file for any outdated marks
+--- Delete a local mark
 ---@param path string
-function M.global_marks.check_for_outdated(path)
-  -- Get all global marks for path
-  local _, gmarks = call_backend(be.list_global_marks, path)
-  for _, m in ipairs(gmarks) do
-    check_for_outdated_marks(m.file, m.lineno, m.line, function(new_lineno)
-      call_backend(be.update_global_mark, m.path, m.lineno, new_lineno, nil)
-      vim.notify("Updated global mark [" .. m.desc .. "] to lineno: " .. new_lineno, vim.log.levels.INFO)
-    end)
+---@param lineno integer
+function M.local_marks.remove(path, lineno)
+  call_backend(be.remove_local_mark, path, lineno)
+end
+
+--- Moves the marks of a file after it changed, using a diff against the old contents
+---@param path string
+---@param old_contents? string Contents before the change, the last known contents are used when nil
+---@return Beez.codestacks.RelocationReport?
+function M.global_marks.check_for_outdated(path, old_contents)
+  local bufnr = vim.fn.bufnr(path)
+  local new_contents = nil
+  if bufnr ~= -1 then
+    new_contents = table.concat(vim.api.nvim_buf_get_lines(bufnr, 0, -1, false), "\n")
+  else
+    local f = io.open(path, "r")
+    if f == nil then
+      return nil
+    end
+    new_contents = f:read("*a")
+    f:close()
   end
 
-  local _, lmarks = call_backend(be.list_local_marks, path)
-  for _, m in ipairs(lmarks) do
-    check_for_outdated_marks(m.file, m.lineno, m.line, function(new_lineno)
-      call_backend(be.update_local_mark, m.path, m.lineno, new_lineno)
-      vim.notify("Updated local mark [" .. m.line .. "] to lineno: " .. new_lineno, vim.log.levels.INFO)
-    end)
+  local ok, report = call_backend(be.relocate_marks, path, old_contents, new_contents)
+  if not ok then
+    return nil
+  end
+  for _, m in ipairs(report.moved) do
+    vim.notify(
+      string.format("Updated %s mark at line %d to lineno: %d", m.kind, m.from, m.to),
+      vim.log.levels.INFO
+    )
+  end
+  for _, m in ipairs(report.ambiguous) do
+    vim.notify(
+      string.format("Could not tell where %s mark at line %d moved, maybe line %d", m.kind, m.from, m.to),
+      vim.log.levels.WARN
+    )
+  end
+  for _, m in ipairs(report.deleted) do
+    vim.notify(string.format("Line of %s mark at line %d was removed", m.kind, m.from), vim.log.levels.WARN)
   end
+  return report
 
   -- local marks = M._marks:list({ file = filename })
   -- for _, m in ipairs(marks) do
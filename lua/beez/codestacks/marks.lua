@@ -1,15 +1,33 @@
 local c = require("beez.codestacks.config")
 
 ---@class Beez.codestacks.GlobalMark
+---@field id string
 ---@field path string
+---@field kind Beez.codestacks.TargetKind
 ---@field desc string
+---@field key? string Register to jump to the mark with, unique within the stack
 ---@field line string
 ---@field lineno integer
+---@field col? integer 0-based column the mark starts at
+---@field end_lineno? integer Last line of the marked range
+---@field end_col? integer
+---@field note? string Multi-line note about the mark
+---@field tags string[]
+---@field priority integer Higher is more important
 ---@field stack string
 
 ---@class Beez.codestacks.LocalMark
+---@field id string
 ---@field path string
+---@field kind Beez.codestacks.TargetKind
 ---@field lineno integer
+---@field col? integer 0-based column the mark starts at
+---@field end_lineno? integer Last line of the marked range
+---@field end_col? integer
+---@field note? string Multi-line note about the mark
+---@field tags string[]
+---@field priority integer Higher is more important
+---@field visited_at integer Unix time the mark was added or last jumped to
 ---@field line string
 
 ---@class Beez.codestacks.Marks
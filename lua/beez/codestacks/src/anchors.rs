@@ -0,0 +1,396 @@
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use serde::{Deserialize, Serialize};
+
+/// Lines of context kept on each side of a marked line
+const CONTEXT_LINES: usize = 2;
+/// Candidates scoring below this are never picked
+const MIN_SCORE: f64 = 0.5;
+/// Candidates scoring within this of the best make a relocation ambiguous. As large as the
+/// distance weight so distance alone does not pick one of several identical lines.
+const AMBIGUITY_MARGIN: f64 = 0.1;
+
+// Weights of the parts of a candidate score, summing to 1
+const LINE_WEIGHT: f64 = 0.6;
+const CONTEXT_WEIGHT: f64 = 0.3;
+const DISTANCE_WEIGHT: f64 = 0.1;
+
+/// Text around a marked line used to find it again after edits
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Anchor {
+    // Hash of the trimmed marked line
+    pub hash: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+// 64-bit FNV-1a parameters
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Hashes a line ignoring surrounding whitespace. Hashes are saved with the marks, so this
+/// uses FNV-1a which unlike the std hasher gives the same result across Rust releases.
+pub fn hash_line(line: &str) -> String {
+    let hash = line
+        .trim()
+        .bytes()
+        .fold(FNV_OFFSET, |h, b| (h ^ u64::from(b)).wrapping_mul(FNV_PRIME));
+    format!("{:x}", hash)
+}
+
+impl Anchor {
+    /// Captures the anchor of a 1-based line
+    pub fn capture<S: AsRef<str>>(lines: &[S], lineno: i32) -> Option<Anchor> {
+        let i = usize::try_from(lineno - 1).ok()?;
+        let line = lines.get(i)?;
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + 1 + CONTEXT_LINES).min(lines.len());
+        Some(Anchor {
+            hash: hash_line(line.as_ref()),
+            before: lines[start..i].iter().map(|l| l.as_ref().to_string()).collect(),
+            after: lines[i + 1..end].iter().map(|l| l.as_ref().to_string()).collect(),
+        })
+    }
+}
+
+/// Where a mark most likely ended up
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocation {
+    pub lineno: i32,
+    // Score of the picked line between 0 and 1
+    pub confidence: f64,
+    // Whether another line scored about as well
+    pub ambiguous: bool,
+}
+
+impl IntoLua for Relocation {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("lineno", self.lineno)?;
+        table.set("confidence", self.confidence)?;
+        table.set("ambiguous", self.ambiguous)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+// Character bigrams of a trimmed line
+fn bigrams(s: &str) -> Vec<(char, char)> {
+    let chars: Vec<char> = s.trim().chars().collect();
+    chars.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+/// Similarity of two lines between 0 and 1, using the dice coefficient of their bigrams
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (a.trim(), b.trim());
+    if a == b {
+        return 1.0;
+    }
+    let (a, mut b) = (bigrams(a), bigrams(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let total = a.len() + b.len();
+    let mut shared = 0;
+    for bigram in a {
+        if let Some(i) = b.iter().position(|x| *x == bigram) {
+            b.swap_remove(i);
+            shared += 1;
+        }
+    }
+    (2 * shared) as f64 / total as f64
+}
+
+// How well the lines around a candidate match the anchor context
+fn context_score(anchor: &Anchor, lines: &[&str], i: usize) -> f64 {
+    let before = anchor.before.iter().rev().enumerate().map(|(k, l)| {
+        let line = i.checked_sub(k + 1).and_then(|j| lines.get(j));
+        line.map_or(0.0, |c| similarity(l, c))
+    });
+    let after = anchor
+        .after
+        .iter()
+        .enumerate()
+        .map(|(k, l)| lines.get(i + k + 1).map_or(0.0, |c| similarity(l, c)));
+    let scores: Vec<f64> = before.chain(after).collect();
+    match scores.len() {
+        // Without context only the line itself can be compared
+        0 => 1.0,
+        n => scores.iter().sum::<f64>() / n as f64,
+    }
+}
+
+/// Finds the line in new contents that best matches a mark previously at lineno.
+/// Without an anchor only the line text and distance are used.
+pub fn relocate(line: &str, anchor: Option<&Anchor>, lineno: i32, lines: &[&str]) -> Option<Relocation> {
+    let hash = anchor.map(|a| a.hash.clone()).unwrap_or_else(|| hash_line(line));
+    let mut scores: Vec<(usize, f64)> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, candidate)| {
+            let line_score = if hash_line(candidate) == hash {
+                1.0
+            } else {
+                similarity(line, candidate)
+            };
+            if line_score < MIN_SCORE {
+                return None;
+            }
+            let context = anchor.map_or(1.0, |a| context_score(a, lines, i));
+            let distance = (i as f64 - (lineno - 1) as f64).abs();
+            let proximity = 1.0 / (1.0 + distance / 10.0);
+            let score =
+                LINE_WEIGHT * line_score + CONTEXT_WEIGHT * context + DISTANCE_WEIGHT * proximity;
+            Some((i, score))
+        })
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let (best, confidence) = *scores.first()?;
+    if confidence < MIN_SCORE {
+        return None;
+    }
+    let ambiguous = scores
+        .get(1)
+        .is_some_and(|(_, s)| confidence - s < AMBIGUITY_MARGIN);
+    Some(Relocation {
+        lineno: best as i32 + 1,
+        confidence,
+        ambiguous,
+    })
+}
+
+/// What happened to a mark when mapping it to new file contents
+#[derive(Clone, Debug, PartialEq)]
+pub enum MarkOutcome {
+    /// The line is still there, possibly at another line number
+    Kept(i32),
+    /// The line was edited and found again
+    Relocated(Relocation),
+    /// Several lines match about as well
+    Ambiguous(Relocation),
+    Deleted,
+}
+
+/// Maps a mark at lineno through a line map of the old to the new contents. Lines that were
+/// changed, or all lines without a map, are searched for using the anchor.
+pub fn map_mark(
+    line: &str,
+    anchor: Option<&Anchor>,
+    lineno: i32,
+    map: Option<&[Option<usize>]>,
+    lines: &[&str],
+    min_confidence: f64,
+) -> MarkOutcome {
+    let i = (lineno - 1).max(0) as usize;
+    if let Some(Some(j)) = map.and_then(|m| m.get(i)) {
+        return MarkOutcome::Kept(*j as i32 + 1);
+    }
+    // Expect the line near where the closest unchanged line above it went
+    let expected = map
+        .and_then(|m| {
+            let prev = i.min(m.len());
+            (0..prev)
+                .rev()
+                .find_map(|k| m[k].map(|j| j as i32 + 1 + (i - k) as i32))
+        })
+        .unwrap_or(lineno);
+    match relocate(line, anchor, expected, lines) {
+        Some(r) if r.ambiguous => MarkOutcome::Ambiguous(r),
+        Some(r) if r.confidence >= min_confidence => MarkOutcome::Relocated(r),
+        _ => MarkOutcome::Deleted,
+    }
+}
+
+/// A mark affected by changes to its file
+#[derive(Clone, Debug)]
+pub struct MarkChange {
+    pub id: String,
+    // Either local or global
+    pub kind: &'static str,
+    pub stack: String,
+    pub path: String,
+    pub from: i32,
+    // Line the mark moved to, or the best candidate of an ambiguous mark
+    pub to: Option<i32>,
+    pub confidence: f64,
+}
+
+impl IntoLua for MarkChange {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("kind", self.kind)?;
+        table.set("stack", self.stack)?;
+        table.set("path", self.path)?;
+        table.set("from", self.from)?;
+        table.set("to", self.to)?;
+        table.set("confidence", self.confidence)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Marks of a file that moved, lost their line or could not be placed with certainty
+#[derive(Clone, Debug, Default)]
+pub struct RelocationReport {
+    pub moved: Vec<MarkChange>,
+    pub deleted: Vec<MarkChange>,
+    pub ambiguous: Vec<MarkChange>,
+}
+
+impl IntoLua for RelocationReport {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("moved", self.moved)?;
+        table.set("deleted", self.deleted)?;
+        table.set("ambiguous", self.ambiguous)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// State of a mark compared to its file on disk
+#[derive(Clone, Debug)]
+pub struct MarkStatus {
+    pub id: String,
+    // Either local or global
+    pub kind: &'static str,
+    pub stack: String,
+    pub path: String,
+    pub lineno: i32,
+    pub line: String,
+    pub desc: Option<String>,
+    // One of ok, file_missing, line_out_of_range, text_changed or relocated
+    pub status: &'static str,
+    // Text now at the marked line
+    pub current_line: Option<String>,
+    // Where the marked line most likely moved to
+    pub candidate: Option<Relocation>,
+}
+
+impl IntoLua for MarkStatus {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("kind", self.kind)?;
+        table.set("stack", self.stack)?;
+        table.set("path", self.path)?;
+        table.set("lineno", self.lineno)?;
+        table.set("line", self.line)?;
+        table.set("desc", self.desc)?;
+        table.set("status", self.status)?;
+        table.set("current_line", self.current_line)?;
+        table.set("candidate", self.candidate)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Compares a mark at lineno with the lines of its file. A changed line that can be found
+/// again with at least min_confidence is reported as relocated.
+pub fn mark_status(
+    line: &str,
+    anchor: Option<&Anchor>,
+    lineno: i32,
+    lines: &[&str],
+    min_confidence: f64,
+) -> (&'static str, Option<String>, Option<Relocation>) {
+    let current = usize::try_from(lineno - 1)
+        .ok()
+        .and_then(|i| lines.get(i))
+        .map(|l| l.to_string());
+    if current.as_ref().is_some_and(|c| c.trim() == line.trim()) {
+        return ("ok", current, None);
+    }
+    let candidate = relocate(line, anchor, lineno, lines);
+    let status = match (&current, &candidate) {
+        (_, Some(r)) if r.lineno != lineno && r.confidence >= min_confidence => "relocated",
+        (None, _) => "line_out_of_range",
+        (Some(_), _) => "text_changed",
+    };
+    (status, current, candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINES: [&str; 7] = [
+        "fn main() {",
+        "    let config = load();",
+        "    run(config);",
+        "}",
+        "",
+        "fn load() -> Config {",
+        "    Config::default()",
+    ];
+
+    #[test]
+    fn hash_is_stable() {
+        // Saved with marks, so it must never change
+        assert_eq!(hash_line("  abc  "), "e71fa2190541574b");
+        assert_eq!(hash_line("abc"), hash_line("\tabc"));
+    }
+
+    #[test]
+    fn similarity_of_lines() {
+        assert_eq!(similarity("  run(config);", "run(config);"), 1.0);
+        assert_eq!(similarity("abc", ""), 0.0);
+        assert_eq!(similarity("ab", "cd"), 0.0);
+        let close = similarity("run(config);", "run(config, true);");
+        assert!(close > 0.5 && close < 1.0, "{close}");
+    }
+
+    #[test]
+    fn relocate_finds_moved_line() {
+        let anchor = Anchor::capture(&LINES, 3);
+        let mut moved = LINES.to_vec();
+        moved.insert(0, "use std::env;");
+        moved.insert(0, "");
+        let r = relocate("    run(config);", anchor.as_ref(), 3, &moved).unwrap();
+        assert_eq!(r.lineno, 5);
+        assert!(!r.ambiguous);
+        assert!(r.confidence > 0.9);
+    }
+
+    #[test]
+    fn relocate_finds_edited_line() {
+        let anchor = Anchor::capture(&LINES, 3);
+        let mut edited = LINES.to_vec();
+        edited[2] = "    run(config, true);";
+        let r = relocate("    run(config);", anchor.as_ref(), 3, &edited).unwrap();
+        assert_eq!(r.lineno, 3);
+        assert!(r.confidence < 1.0);
+    }
+
+    #[test]
+    fn relocate_reports_identical_lines_as_ambiguous() {
+        let lines = ["x", "}", "y", "}", "z"];
+        let r = relocate("}", None, 3, &lines).unwrap();
+        assert!(r.ambiguous);
+        assert_eq!(relocate("nothing like it", None, 1, &lines), None);
+    }
+
+    #[test]
+    fn map_mark_keeps_mapped_lines() {
+        let map = [Some(1), Some(2), Some(3), Some(4), Some(5), Some(6), Some(7)];
+        let outcome = map_mark("    run(config);", None, 3, Some(&map), &LINES, 0.5);
+        assert_eq!(outcome, MarkOutcome::Kept(4));
+    }
+
+    #[test]
+    fn map_mark_relocates_changed_lines() {
+        let anchor = Anchor::capture(&LINES, 3);
+        let mut edited = LINES.to_vec();
+        edited[2] = "    run(config, true);";
+        let map = [Some(0), Some(1), None, Some(3), Some(4), Some(5), Some(6)];
+        let outcome = map_mark("    run(config);", anchor.as_ref(), 3, Some(&map), &edited, 0.5);
+        assert!(matches!(outcome, MarkOutcome::Relocated(r) if r.lineno == 3));
+    }
+
+    #[test]
+    fn map_mark_deletes_removed_lines() {
+        let anchor = Anchor::capture(&LINES, 3);
+        let mut removed = LINES.to_vec();
+        removed.remove(2);
+        let map = [Some(0), Some(1), None, Some(2), Some(3), Some(4), Some(5)];
+        let outcome = map_mark("    run(config);", anchor.as_ref(), 3, Some(&map), &removed, 0.9);
+        assert_eq!(outcome, MarkOutcome::Deleted);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::paths::DedupeReport;
+use crate::renames;
+use crate::targets::{self, Target, TargetKind};
 use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
 use serde::{Deserialize, Serialize};
 use std::{
@@ -7,10 +10,42 @@ use std::{
     path::{Path, PathBuf},
 };
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PinnedBuffer {
     pub path: String,
     pub label: String,
+    #[serde(default)]
+    pub kind: TargetKind,
+    // Last known cursor position in the buffer
+    #[serde(default)]
+    pub lnum: Option<i32>,
+    #[serde(default)]
+    pub col: Option<i32>,
+    // Temporary pins can be cleared all at once and may expire
+    #[serde(default)]
+    pub temp: bool,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+impl PinnedBuffer {
+    pub fn new(path: String, label: String) -> Self {
+        let target = Target::parse(&path);
+        PinnedBuffer {
+            path: target.path,
+            label,
+            kind: target.kind,
+            lnum: None,
+            col: None,
+            temp: false,
+            expires_at: None,
+        }
+    }
+
+    // Whether this is a temporary pin past its expiry time
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.temp && self.expires_at.is_some_and(|t| t <= now)
+    }
 }
 
 impl IntoLua for PinnedBuffer {
@@ -18,6 +53,11 @@ impl IntoLua for PinnedBuffer {
         let table = lua.create_table()?;
         table.set("path", self.path)?;
         table.set("label", self.label)?;
+        table.set("kind", self.kind)?;
+        table.set("lnum", self.lnum)?;
+        table.set("col", self.col)?;
+        table.set("temp", self.temp)?;
+        table.set("expires_at", self.expires_at)?;
         Ok(LuaValue::Table(table))
     }
 }
@@ -73,6 +113,7 @@ impl RecentFiles {
         if !self.enabled {
             return;
         }
+        let file_path = targets::normalize(&file_path);
 
         // Get rid of duplicates and move to front
         self.files.retain(|p| *p != file_path);
@@ -85,6 +126,7 @@ impl RecentFiles {
         if !self.enabled {
             return;
         }
+        let file_path = targets::normalize(&file_path);
         self.files.retain(|p| *p != file_path);
         self.save();
     }
@@ -94,6 +136,69 @@ impl RecentFiles {
         self.files.clone()
     }
 
+    // Normalizes all paths and removes the duplicates this creates, keeping the most recent
+    pub fn dedupe_paths(&mut self) -> DedupeReport {
+        let mut report = DedupeReport::default();
+        let mut files: Vec<String> = Vec::new();
+        for file in &self.files {
+            let path = targets::normalize(file);
+            if &path != file {
+                report.rewritten += 1;
+            }
+            if files.contains(&path) {
+                report.recent_files += 1;
+            } else {
+                files.push(path);
+            }
+        }
+        self.files = files;
+        if !report.is_empty() {
+            self.save();
+        }
+        report
+    }
+
+    // Points files at old, or inside it, to new and returns how many were changed
+    pub fn rename_path(&mut self, old: &str, new: &str) -> usize {
+        let (old, new) = (targets::normalize(old), targets::normalize(new));
+        let mut renamed = 0;
+        for file in self.files.iter_mut() {
+            if let Some(p) = renames::rebase(file, &old, &new) {
+                *file = p;
+                renamed += 1;
+            }
+        }
+        if renamed > 0 {
+            // A renamed file may already be in the list under its new path
+            let mut seen: Vec<String> = Vec::new();
+            self.files.retain(|f| {
+                let keep = !seen.contains(f);
+                seen.push(f.clone());
+                keep
+            });
+            self.save();
+        }
+        renamed
+    }
+
+    // Returns recent files that no longer exist
+    pub fn dangling(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|f| Target::parse(f).exists() == Some(false))
+            .cloned()
+            .collect()
+    }
+
+    // Removes the given files from the list
+    pub fn prune(&mut self, files: &[String]) {
+        let original_len = self.files.len();
+        self.files.retain(|f| !files.contains(f));
+        if self.files.len() != original_len {
+            self.save();
+        }
+    }
+
     // Sets enabled status
     pub fn set_enabled(&mut self, enabled: bool) {
         self.enabled = enabled;
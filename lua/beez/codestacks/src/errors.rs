@@ -5,6 +5,8 @@ pub enum Errors {
     AcquireStacksLock,
     #[error("Stacks has not been initialized. Please call setup first.")]
     StacksNotInit,
+    #[error("Stacks file {0} could not be parsed, changes are not saved until it is repaired: {1}")]
+    StacksUnparsable(String, String),
     #[error("Failed to acquire lock for recent files")]
     AcquireRecentFilesLock,
     #[error("Recent files has not been initialized. Please call setup first.")]
@@ -19,6 +21,38 @@ pub enum Errors {
     AcquireFrecencyLock,
     #[error("Failed to create directory: {0}")]
     CreateDir(#[from] std::io::Error),
+    #[error("Invalid group by {0}, expected one of day, week or stack")]
+    InvalidGroupBy(String),
+    #[error("Failed to read templates: {0}")]
+    ReadTemplates(#[source] std::io::Error),
+    #[error("Failed to parse templates: {0}")]
+    ParseTemplates(#[source] serde_json::Error),
+    #[error("Template {0} does not exist")]
+    TemplateNotFound(String),
+    #[error("Missing value for placeholder {{{0}}} in {1}")]
+    TemplateVarMissing(String, String),
+    #[error("Invalid conflict policy {0}, expected one of reject, replace or swap")]
+    InvalidConflictPolicy(String),
+    #[error("Stack {0} already exists")]
+    StackExists(String),
+    #[error("No stack, snapshot or file named {0}")]
+    StackNotFound(String),
+    #[error("Failed to read snapshot: {0}")]
+    ReadSnapshot(#[source] std::io::Error),
+    #[error("Failed to write snapshot: {0}")]
+    WriteSnapshot(#[source] std::io::Error),
+    #[error("Failed to read or write notes: {0}")]
+    Notes(#[source] std::io::Error),
+    #[error("Failed to serialize or parse stack: {0}")]
+    ParseStack(#[source] serde_json::Error),
+    #[error("Invalid gc mode {0}, expected one of dry_run, prune or archive")]
+    InvalidGcMode(String),
+    #[error("Invalid mark kind {0}, expected local or global")]
+    InvalidMarkKind(String),
+    #[error("Failed to write archive: {0}")]
+    WriteArchive(#[source] std::io::Error),
+    #[error("Failed to get current directory: {0}")]
+    CurrentDir(#[source] std::io::Error),
     // #[error("Failed to open frecency database env: {0}")]
     // EnvOpen(#[source] heed::Error),
     // #[error("Failed to create frecency database: {0}")]
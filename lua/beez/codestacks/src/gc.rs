@@ -0,0 +1,70 @@
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+
+/// What to do with references to files that no longer exist
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GcMode {
+    /// Only report dangling references
+    DryRun,
+    /// Remove dangling references
+    Prune,
+    /// Remove dangling references after writing them to the archive dir
+    Archive,
+}
+
+impl GcMode {
+    pub fn parse(s: &str) -> Option<GcMode> {
+        match s {
+            "dry_run" => Some(GcMode::DryRun),
+            "prune" => Some(GcMode::Prune),
+            "archive" => Some(GcMode::Archive),
+            _ => None,
+        }
+    }
+}
+
+/// A pin or mark pointing at a file that no longer exists
+#[derive(Clone, Debug)]
+pub struct DanglingRef {
+    pub stack: String,
+    // One of pin, local_mark or global_mark
+    pub kind: &'static str,
+    pub path: String,
+    pub label: Option<String>,
+    pub pin_list: Option<String>,
+    pub lineno: Option<i32>,
+}
+
+impl IntoLua for DanglingRef {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("stack", self.stack)?;
+        table.set("kind", self.kind)?;
+        table.set("path", self.path)?;
+        table.set("label", self.label)?;
+        table.set("pin_list", self.pin_list)?;
+        table.set("lineno", self.lineno)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Dangling references found by a gc run and what was done with them
+#[derive(Clone, Debug, Default)]
+pub struct GcReport {
+    pub refs: Vec<DanglingRef>,
+    pub recent_files: Vec<String>,
+    // Whether the references were removed
+    pub pruned: bool,
+    // File the removed references were written to
+    pub archive: Option<String>,
+}
+
+impl IntoLua for GcReport {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("refs", self.refs)?;
+        table.set("recent_files", self.recent_files)?;
+        table.set("pruned", self.pruned)?;
+        table.set("archive", self.archive)?;
+        Ok(LuaValue::Table(table))
+    }
+}
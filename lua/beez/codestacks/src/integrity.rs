@@ -0,0 +1,42 @@
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+
+/// A problem found in project data
+#[derive(Clone, Debug)]
+pub struct Issue {
+    // None for files shared by all projects
+    pub project: Option<String>,
+    pub stack: Option<String>,
+    // Short identifier of the check that failed, ie duplicate_label
+    pub check: &'static str,
+    pub message: String,
+    pub repaired: bool,
+}
+
+impl Issue {
+    pub fn new(
+        project: Option<&str>,
+        stack: Option<&str>,
+        check: &'static str,
+        message: String,
+    ) -> Self {
+        Issue {
+            project: project.map(String::from),
+            stack: stack.map(String::from),
+            check,
+            message,
+            repaired: false,
+        }
+    }
+}
+
+impl IntoLua for Issue {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("project", self.project)?;
+        table.set("stack", self.stack)?;
+        table.set("check", self.check)?;
+        table.set("message", self.message)?;
+        table.set("repaired", self.repaired)?;
+        Ok(LuaValue::Table(table))
+    }
+}
@@ -0,0 +1,209 @@
+use crate::buffers::PinnedBuffer;
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+
+/// Default alphabet of pin labels
+pub fn default_labels() -> Vec<String> {
+    ('a'..='z')
+        .chain('A'..='Z')
+        .chain('1'..='9')
+        .map(|c| c.to_string())
+        .collect()
+}
+
+/// What to do when pinning with a label that is already used by another buffer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Leave the existing pin alone and do not pin
+    Reject,
+    /// Unpin the buffer currently using the label
+    Replace,
+    /// Give the buffer currently using the label the old label of the pinned buffer
+    Swap,
+}
+
+impl ConflictPolicy {
+    pub fn parse(s: &str) -> Option<ConflictPolicy> {
+        match s {
+            "reject" => Some(ConflictPolicy::Reject),
+            "replace" => Some(ConflictPolicy::Replace),
+            "swap" => Some(ConflictPolicy::Swap),
+            _ => None,
+        }
+    }
+}
+
+/// Result of pinning a buffer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinStatus {
+    Pinned,
+    Relabeled,
+    Unchanged,
+    Rejected,
+    Replaced,
+    Swapped,
+    InvalidLabel,
+    NoFreeLabel,
+}
+
+impl PinStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PinStatus::Pinned => "pinned",
+            PinStatus::Relabeled => "relabeled",
+            PinStatus::Unchanged => "unchanged",
+            PinStatus::Rejected => "rejected",
+            PinStatus::Replaced => "replaced",
+            PinStatus::Swapped => "swapped",
+            PinStatus::InvalidLabel => "invalid_label",
+            PinStatus::NoFreeLabel => "no_free_label",
+        }
+    }
+
+    /// Whether the pins were modified
+    pub fn changed(&self) -> bool {
+        matches!(
+            self,
+            PinStatus::Pinned | PinStatus::Relabeled | PinStatus::Replaced | PinStatus::Swapped
+        )
+    }
+}
+
+/// Describes what happened when pinning a buffer
+#[derive(Clone, Debug)]
+pub struct PinOutcome {
+    pub status: PinStatus,
+    pub path: String,
+    pub label: Option<String>,
+    // Path of the buffer that was unpinned to make room
+    pub evicted: Option<String>,
+    // Pinned buffer that had its label swapped
+    pub swapped: Option<PinnedBuffer>,
+}
+
+impl PinOutcome {
+    pub fn new(status: PinStatus, path: String, label: Option<String>) -> Self {
+        PinOutcome {
+            status,
+            path,
+            label,
+            evicted: None,
+            swapped: None,
+        }
+    }
+}
+
+impl IntoLua for PinOutcome {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("status", self.status.as_str())?;
+        table.set("path", self.path)?;
+        table.set("label", self.label)?;
+        table.set("evicted", self.evicted)?;
+        table.set("swapped", self.swapped)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Returns the first label of the alphabet not used by any pin
+pub fn next_free_label(pins: &[PinnedBuffer], alphabet: &[String]) -> Option<String> {
+    alphabet
+        .iter()
+        .find(|l| !pins.iter().any(|p| &p.label == *l))
+        .cloned()
+}
+
+/// Relabels temporary pins so they use the first free temporary labels, keeping their order
+pub fn compact_temp_labels(pins: &mut [PinnedBuffer], temp_labels: &[String]) {
+    let label_index = |l: &String| temp_labels.iter().position(|t| t == l).unwrap_or(usize::MAX);
+    let mut temp: Vec<usize> = (0..pins.len()).filter(|i| pins[*i].temp).collect();
+    temp.sort_by_key(|i| label_index(&pins[*i].label));
+    let free: Vec<String> = temp_labels
+        .iter()
+        .filter(|l| !pins.iter().any(|p| !p.temp && &p.label == *l))
+        .cloned()
+        .collect();
+    for (i, l) in temp.into_iter().zip(free) {
+        pins[i].label = l;
+    }
+}
+
+/// Pins a path with a label, resolving label conflicts with the given policy.
+/// When no label is given the next free label of the alphabet is used.
+pub fn pin(
+    pins: &mut Vec<PinnedBuffer>,
+    path: String,
+    label: Option<String>,
+    policy: ConflictPolicy,
+    alphabet: &[String],
+) -> PinOutcome {
+    let label = match label {
+        Some(l) => l,
+        None => {
+            if let Some(pb) = pins.iter().find(|p| p.path == path) {
+                return PinOutcome::new(PinStatus::Unchanged, path, Some(pb.label.clone()));
+            }
+            match next_free_label(pins, alphabet) {
+                Some(l) => l,
+                None => return PinOutcome::new(PinStatus::NoFreeLabel, path, None),
+            }
+        }
+    };
+    if !alphabet.contains(&label) {
+        return PinOutcome::new(PinStatus::InvalidLabel, path, Some(label));
+    }
+
+    let current = pins.iter().position(|p| p.path == path);
+    let conflict = pins.iter().position(|p| p.path != path && p.label == label);
+    if let Some(i) = current
+        && pins[i].label == label
+    {
+        return PinOutcome::new(PinStatus::Unchanged, path, Some(label));
+    }
+
+    let mut outcome = PinOutcome::new(PinStatus::Pinned, path.clone(), Some(label.clone()));
+    if let Some(c) = conflict {
+        match policy {
+            ConflictPolicy::Reject => {
+                outcome.status = PinStatus::Rejected;
+                return outcome;
+            }
+            ConflictPolicy::Replace => {
+                outcome.status = PinStatus::Replaced;
+                outcome.evicted = Some(pins[c].path.clone());
+            }
+            ConflictPolicy::Swap => {
+                // A buffer that was not pinned yet has no label to give away
+                let other_label = match current {
+                    Some(i) => Some(pins[i].label.clone()),
+                    None => next_free_label(pins, alphabet),
+                };
+                match other_label {
+                    Some(l) => {
+                        pins[c].label = l;
+                        outcome.status = PinStatus::Swapped;
+                        outcome.swapped = Some(pins[c].clone());
+                    }
+                    None => {
+                        outcome.status = PinStatus::NoFreeLabel;
+                        return outcome;
+                    }
+                }
+            }
+        }
+    }
+
+    // Re-pinning a buffer keeps its position
+    match current {
+        Some(i) => {
+            pins[i].label = label;
+            if outcome.status == PinStatus::Pinned {
+                outcome.status = PinStatus::Relabeled;
+            }
+        }
+        None => pins.push(PinnedBuffer::new(path, label)),
+    }
+    if let Some(evicted) = &outcome.evicted {
+        pins.retain(|p| &p.path != evicted);
+    }
+    outcome
+}
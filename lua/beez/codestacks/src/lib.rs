@@ -1,14 +1,31 @@
 // src/lib.rs
 use mlua::prelude::*;
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::sync::RwLock;
+mod anchors;
 pub mod buffers;
 mod errors;
+mod gc;
+mod integrity;
+mod labels;
+mod linediff;
 pub mod marks;
+mod notes;
+mod options;
+mod paths;
+mod quickfix;
+mod renames;
+mod search;
+mod stackdiff;
 mod stacks;
+mod targets;
+mod templates;
+mod timetracking;
 mod tracing;
 use crate::{buffers::RecentFiles, stacks::StacksManager};
 use errors::Errors;
+use options::Options;
 use stacks::Stack;
 
 pub static STACKS: Lazy<RwLock<Option<StacksManager>>> = Lazy::new(|| RwLock::new(None));
@@ -23,13 +40,15 @@ pub fn init_tracing(_: &Lua, (log_file_path, log_level): (String, Option<String>
 /// Setup stacks
 pub fn setup(
     _: &Lua,
-    (project, base_dir, recent_files_limit): (String, String, i32),
+    (project, base_dir, recent_files_limit, options): (String, String, i32, Option<Options>),
 ) -> LuaResult<bool> {
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     if stacks_man.is_some() {
         return Ok(false);
     }
-    *stacks_man = Some(StacksManager::new(project.clone(), &base_dir));
+    let options = options.unwrap_or_default();
+    paths::set_resolve_symlinks(options.resolve_symlinks);
+    *stacks_man = Some(StacksManager::new(project.clone(), &base_dir, options));
 
     ::tracing::info!("Stacks initialized...");
 
@@ -48,7 +67,7 @@ pub fn add_stack(_: &Lua, name: String) -> LuaResult<bool> {
     ::tracing::info!("Adding new stack: {}", name);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         Some(ss) => Ok(ss.add(name)),
         None => Ok(false),
     }
@@ -59,7 +78,7 @@ pub fn remove_stack(_: &Lua, name: String) -> LuaResult<Option<Stack>> {
     ::tracing::info!("Removing stack: {}", name);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         None => Ok(None),
         Some(ss) => Ok(ss.remove(name)),
     }
@@ -91,7 +110,7 @@ pub fn set_active_stack(_: &Lua, name: String) -> LuaResult<bool> {
     ::tracing::info!("Setting active stack: {}", name);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         Some(ss) => Ok(ss.set_active(name)),
         None => Ok(false),
     }
@@ -113,7 +132,7 @@ pub fn rename_stack(_: &Lua, (old_name, new_name): (String, String)) -> LuaResul
     ::tracing::info!("Renaming stack from {} to {}", old_name, new_name);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         Some(ss) => Ok(ss.rename(old_name, new_name)),
         None => Ok(false),
     }
@@ -130,13 +149,72 @@ pub fn get_stack(_: &Lua, name: Option<String>) -> LuaResult<Option<Stack>> {
     }
 }
 
-// Pins a buffer to the active stack with a label
-pub fn pin_buffer(_: &Lua, (path, label): (String, String)) -> LuaResult<bool> {
-    ::tracing::info!("Pinning buffer {} with label: {}", path, label);
+// Parses an optional label conflict policy
+fn parse_policy(policy: Option<String>) -> Result<Option<labels::ConflictPolicy>, Errors> {
+    match policy {
+        Some(p) => labels::ConflictPolicy::parse(&p)
+            .map(Some)
+            .ok_or(Errors::InvalidConflictPolicy(p)),
+        None => Ok(None),
+    }
+}
+
+// Pins a buffer to the active stack with a label, or the next free label
+pub fn pin_buffer(
+    _: &Lua,
+    (path, label, policy): (String, Option<String>, Option<String>),
+) -> LuaResult<Option<labels::PinOutcome>> {
+    ::tracing::info!("Pinning buffer {} with label: {:?}", path, label);
+    let policy = parse_policy(policy)?;
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.pin_buffer(path, label, policy)),
+        None => Ok(None),
+    }
+}
+
+// Pins a buffer to the active stack with the next free temporary label
+pub fn pin_temp_buffer(
+    _: &Lua,
+    (path, ttl): (String, Option<i64>),
+) -> LuaResult<Option<labels::PinOutcome>> {
+    ::tracing::info!("Pinning temporary buffer {} with ttl: {:?}", path, ttl);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
-        Some(ss) => Ok(ss.pin_buffer(path, label)),
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.pin_temp_buffer(path, ttl)),
+        None => Ok(None),
+    }
+}
+
+// Removes all temporary pins from the active stack
+pub fn clear_temp_pins(_: &Lua, _: ()) -> LuaResult<Vec<buffers::PinnedBuffer>> {
+    ::tracing::info!("Clearing temporary pins...");
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.clear_temp_pins()),
+        None => Ok(vec![]),
+    }
+}
+
+// Returns the next label not used by a pin, from the given labels or the configured ones
+pub fn next_free_label(_: &Lua, labels: Option<Vec<String>>) -> LuaResult<Option<String>> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.next_free_label(labels)),
+        None => Ok(None),
+    }
+}
+
+// Checks whether a label can be used to pin a buffer
+pub fn is_valid_label(_: &Lua, label: String) -> LuaResult<bool> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.is_valid_label(label)),
         None => Ok(false),
     }
 }
@@ -146,12 +224,102 @@ pub fn unpin_buffer(_: &Lua, path: String) -> LuaResult<bool> {
     ::tracing::info!("Unpinning buffer {}", path);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         None => Ok(false),
         Some(ss) => Ok(ss.unpin_buffer(path)),
     }
 }
 
+// Pins a buffer to the active stack at a 1-based position
+pub fn insert_pin_at(
+    _: &Lua,
+    (path, label, index, policy): (String, Option<String>, usize, Option<String>),
+) -> LuaResult<Option<labels::PinOutcome>> {
+    ::tracing::info!("Pinning buffer {} with label {:?} at {}", path, label, index);
+    let policy = parse_policy(policy)?;
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.insert_pin_at(path, label, index, policy)),
+        None => Ok(None),
+    }
+}
+
+// Moves a pinned buffer to a 1-based position in the active stack
+pub fn move_pin(_: &Lua, (path, index): (String, usize)) -> LuaResult<bool> {
+    ::tracing::info!("Moving pinned buffer {} to {}", path, index);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.move_pin(path, index)),
+        None => Ok(false),
+    }
+}
+
+// Swaps the positions of two pinned buffers in the active stack
+pub fn swap_pins(_: &Lua, (a, b): (String, String)) -> LuaResult<bool> {
+    ::tracing::info!("Swapping pinned buffers {} and {}", a, b);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.swap_pins(a, b)),
+        None => Ok(false),
+    }
+}
+
+// Records the last cursor position of a pinned buffer in the active stack
+pub fn update_pin_cursor(_: &Lua, (path, lnum, col): (String, i32, i32)) -> LuaResult<bool> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.update_pin_cursor(path, lnum, col)),
+        None => Ok(false),
+    }
+}
+
+// Saves any changes that are only kept in memory
+pub fn flush(_: &Lua, _: ()) -> LuaResult<bool> {
+    ::tracing::info!("Flushing stacks...");
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.flush()),
+        None => Ok(false),
+    }
+}
+
+// Makes a named pin list of the active stack visible, creating it if needed
+pub fn set_pin_list(_: &Lua, name: String) -> LuaResult<bool> {
+    ::tracing::info!("Switching to pin list: {}", name);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.set_pin_list(name)),
+        None => Ok(false),
+    }
+}
+
+// Lists the pin lists of the active stack
+pub fn list_pin_lists(_: &Lua, _: ()) -> LuaResult<Vec<stacks::PinList>> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.list_pin_lists()),
+        None => Ok(vec![]),
+    }
+}
+
+// Removes a pin list from the active stack
+pub fn remove_pin_list(_: &Lua, name: String) -> LuaResult<bool> {
+    ::tracing::info!("Removing pin list: {}", name);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.remove_pin_list(name)),
+        None => Ok(false),
+    }
+}
+
 // Returns a list of pinned buffers in the active stack
 pub fn list_pinned_buffers(_: &Lua, _: ()) -> LuaResult<Vec<buffers::PinnedBuffer>> {
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
@@ -173,6 +341,85 @@ pub fn get_pinned_buffer(_: &Lua, path: String) -> LuaResult<Option<buffers::Pin
     }
 }
 
+// Normalizes the paths of all pins, marks and recent files and removes the duplicates this creates
+pub fn dedupe_paths(_: &Lua, _: ()) -> LuaResult<paths::DedupeReport> {
+    ::tracing::info!("Deduplicating paths");
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    let mut report = sm.dedupe_paths();
+    let mut recent_files = RECENT_FILES.write().map_err(|_| Errors::AcquireRecentFilesLock)?;
+    if let Some(rf) = recent_files.as_mut() {
+        report.merge(rf.dedupe_paths());
+    }
+    Ok(report)
+}
+
+// Points all pins, marks and recent files at old, or inside it, to new
+pub fn rename_path(_: &Lua, (old, new): (String, String)) -> LuaResult<renames::RenameReport> {
+    ::tracing::info!("Renaming path {} to {}", old, new);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    let mut report = sm.rename_path(&old, &new);
+    let mut recent_files = RECENT_FILES.write().map_err(|_| Errors::AcquireRecentFilesLock)?;
+    if let Some(rf) = recent_files.as_mut() {
+        report.recent_files = rf.rename_path(&old, &new);
+    }
+    Ok(report)
+}
+
+// Finds likely new locations of a missing file below root, defaulting to the current directory
+pub fn find_moved_path(
+    _: &Lua,
+    (path, root): (String, Option<String>),
+) -> LuaResult<Vec<renames::MoveCandidate>> {
+    ::tracing::info!("Finding new location of {}", path);
+    let root = match root {
+        Some(r) => std::path::PathBuf::from(paths::normalize(&r)),
+        None => std::env::current_dir().map_err(Errors::CurrentDir)?,
+    };
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    Ok(sm.find_moved_path(&path, &root))
+}
+
+// Reports pins, marks and recent files of files that no longer exist. Mode is one of dry_run
+// (default), prune or archive.
+pub fn gc(_: &Lua, mode: Option<String>) -> LuaResult<Option<gc::GcReport>> {
+    ::tracing::info!("Collecting dangling references: {:?}", mode);
+    let mode = match mode {
+        Some(m) => gc::GcMode::parse(&m).ok_or(Errors::InvalidGcMode(m))?,
+        None => gc::GcMode::DryRun,
+    };
+    // Stacks are always locked before recent files
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    let mut recent_files = RECENT_FILES.write().map_err(|_| Errors::AcquireRecentFilesLock)?;
+    let dangling_files = recent_files.as_ref().map(|rf| rf.dangling()).unwrap_or_default();
+    let report = match sm.get_stacks_writable()? {
+        Some(ss) => ss.gc(mode, dangling_files)?,
+        None => return Ok(None),
+    };
+    if report.pruned
+        && let Some(rf) = recent_files.as_mut()
+    {
+        rf.prune(&report.recent_files);
+    }
+    Ok(Some(report))
+}
+
+// Validates the data of all projects and optionally repairs what can be repaired
+pub fn check_integrity(_: &Lua, repair: Option<bool>) -> LuaResult<Vec<integrity::Issue>> {
+    ::tracing::info!("Checking integrity, repair: {:?}", repair);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    Ok(sm.check_integrity(repair.unwrap_or(false)))
+}
+
+// Returns the kind and normalized form of a path and whether it exists
+pub fn get_target(_: &Lua, path: String) -> LuaResult<targets::Target> {
+    Ok(targets::Target::parse(&path))
+}
+
 // Adds a file to the recent files list
 pub fn add_recent_file(_: &Lua, file_path: String) -> LuaResult<bool> {
     ::tracing::info!("Adding recent file: {}", file_path);
@@ -210,13 +457,13 @@ pub fn enable_recent_files(_: &Lua, enable: bool) -> LuaResult<bool> {
 // Add global mark to the active stack
 pub fn add_global_mark(
     _: &Lua,
-    (path, desc, line, lineno): (String, String, String, i32),
+    (path, desc, line, lineno, opts): (String, String, String, i32, marks::MarkOpts),
 ) -> LuaResult<bool> {
     ::tracing::info!("Adding global mark: {} - {}", path, desc);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
-        Some(ss) => Ok(ss.add_global_mark(path, desc, line, lineno)),
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.add_global_mark(path, desc, line, lineno, opts)),
         None => Ok(false),
     }
 }
@@ -226,20 +473,24 @@ pub fn remove_global_mark(_: &Lua, (path, lineno): (String, i32)) -> LuaResult<b
     ::tracing::info!("Removing global mark: {} at line {}", path, lineno);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         Some(ss) => Ok(ss.remove_global_mark(path, lineno)),
         None => Ok(false),
     }
 }
 
 // Return list of global marks in the active stack
-pub fn list_global_marks(_: &Lua, path: Option<String>) -> LuaResult<Vec<marks::GlobalMark>> {
+pub fn list_global_marks(
+    _: &Lua,
+    (path, filter): (Option<String>, marks::MarkFilter),
+) -> LuaResult<Vec<marks::GlobalMark>> {
     ::tracing::info!("Listing global marks for path: {:?}", path);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
     match sm.get_stacks() {
         Some(ss) => {
             let mut marks = ss.list_global_marks(path);
+            marks.retain(|m| m.matches(&filter));
             marks.sort_by(|a, b| a.desc.cmp(&b.desc));
             Ok(marks)
         }
@@ -259,28 +510,203 @@ pub fn list_all_global_marks(_: &Lua, path: Option<String>) -> LuaResult<Vec<mar
     }
 }
 
+// Returns the next mark after a line of the active stack in file and line order, wrapping around.
+// Kind can be local or global to only walk those marks.
+pub fn next_mark(
+    _: &Lua,
+    (path, lineno, kind): (String, i32, Option<String>),
+) -> LuaResult<Option<marks::MarkPos>> {
+    ::tracing::info!("Finding next mark after {}:{}", path, lineno);
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.next_mark(path, lineno, kind.as_deref())),
+        None => Ok(None),
+    }
+}
+
+// Returns the previous mark before a line of the active stack in file and line order, wrapping
+// around
+pub fn prev_mark(
+    _: &Lua,
+    (path, lineno, kind): (String, i32, Option<String>),
+) -> LuaResult<Option<marks::MarkPos>> {
+    ::tracing::info!("Finding previous mark before {}:{}", path, lineno);
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.prev_mark(path, lineno, kind.as_deref())),
+        None => Ok(None),
+    }
+}
+
+// Lists marks and pins of the active stack as quickfix items. Kinds can be global, local and
+// pins, all are listed by default.
+pub fn export_quickfix(_: &Lua, kinds: Option<Vec<String>>) -> LuaResult<Vec<quickfix::QfItem>> {
+    ::tracing::info!("Exporting quickfix items: {:?}", kinds);
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.export_quickfix(&kinds.unwrap_or_default())),
+        None => Ok(vec![]),
+    }
+}
+
+// Lists marks and pins of the active stack as file:line:col: text lines
+pub fn export_errorformat(lua: &Lua, kinds: Option<Vec<String>>) -> LuaResult<String> {
+    let items = export_quickfix(lua, kinds)?;
+    Ok(quickfix::to_errorformat(&items))
+}
+
+// Adds quickfix items to the active stack as local or global marks
+pub fn import_quickfix(_: &Lua, (items, kind): (Vec<quickfix::QfItem>, String)) -> LuaResult<usize> {
+    ::tracing::info!("Importing {} quickfix items as {} marks", items.len(), kind);
+    let kind = quickfix::ImportKind::parse(&kind).ok_or(Errors::InvalidMarkKind(kind))?;
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.import_quickfix(items, kind)),
+        None => Ok(0),
+    }
+}
+
+// Adds file:line:col: text lines to the active stack as local or global marks
+pub fn import_errorformat(lua: &Lua, (text, kind): (String, String)) -> LuaResult<usize> {
+    import_quickfix(lua, (quickfix::parse_errorformat(&text), kind))
+}
+
+// Compares the marks of the active stack, or of all stacks, with their files on disk
+pub fn validate_marks(_: &Lua, all_stacks: Option<bool>) -> LuaResult<Vec<anchors::MarkStatus>> {
+    ::tracing::info!("Validating marks, all stacks: {:?}", all_stacks);
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.validate_marks(all_stacks.unwrap_or(false))),
+        None => Ok(vec![]),
+    }
+}
+
+// Fuzzy searches marks and pins of the active project, or of all projects
+pub fn search(
+    _: &Lua,
+    (query, all_projects, limit): (String, Option<bool>, Option<usize>),
+) -> LuaResult<Vec<search::SearchHit>> {
+    ::tracing::info!("Searching for: {}", query);
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    Ok(sm.search(&query, all_projects.unwrap_or(false), limit))
+}
+
+// Sets or clears the key of a global mark in the active stack
+pub fn set_mark_key(_: &Lua, (path, lineno, key): (String, i32, Option<String>)) -> LuaResult<bool> {
+    ::tracing::info!("Setting key of global mark {}:{} to {:?}", path, lineno, key);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.set_mark_key(path, lineno, key)),
+        None => Ok(false),
+    }
+}
+
+// Returns the global mark with the given key in the active stack
+pub fn get_mark_by_key(_: &Lua, key: String) -> LuaResult<Option<marks::GlobalMark>> {
+    ::tracing::info!("Getting global mark by key: {}", key);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.get_mark_by_key(&key)),
+        None => Ok(None),
+    }
+}
+
 // Updates a global mark
 pub fn update_global_mark(
     _: &Lua,
-    (path, lineno, new_lineno, new_desc): (String, i32, Option<i32>, Option<String>),
+    (path, lineno, new_lineno, new_desc, opts): (
+        String,
+        i32,
+        Option<i32>,
+        Option<String>,
+        marks::MarkOpts,
+    ),
 ) -> LuaResult<bool> {
     ::tracing::info!("Updating global mark for path: {} at line: {}", path, lineno);
     ::tracing::info!("new_lineno={:?}, desc={:?}", new_lineno, new_desc);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.update_global_mark(path, lineno, new_lineno, new_desc, opts)),
+        None => Ok(false),
+    }
+}
+
+// Finds where a mark most likely is in the given contents, or in its file on disk
+pub fn locate_mark(
+    _: &Lua,
+    (id, contents): (String, Option<String>),
+) -> LuaResult<Option<anchors::Relocation>> {
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.locate_mark(&id, contents)),
+        None => Ok(None),
+    }
+}
+
+// Whether any stack of the active project has marks in a file
+pub fn has_marks(_: &Lua, path: String) -> LuaResult<bool> {
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.has_marks(&path)),
+        None => Ok(false),
+    }
+}
+
+// Maps the marks of a file in all stacks from its old to its new contents
+pub fn relocate_marks(
+    _: &Lua,
+    (path, old_contents, new_contents): (String, Option<String>, String),
+) -> LuaResult<anchors::RelocationReport> {
+    ::tracing::info!("Relocating marks of {}", path);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.relocate_marks(path, old_contents, new_contents)),
+        None => Ok(anchors::RelocationReport::default()),
+    }
+}
+
+// Shifts marks of a file after its buffer lines first..last were replaced by first..new_last
+pub fn shift_marks(_: &Lua, (path, first, last, new_last): (String, i32, i32, i32)) -> LuaResult<bool> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.shift_marks(path, first, last, new_last)),
+        None => Ok(false),
+    }
+}
+
+// Moves marks shifted by unwritten edits to a buffer back to where they are on disk
+pub fn discard_mark_shifts(_: &Lua, path: String) -> LuaResult<bool> {
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
     match sm.get_stacks_mut() {
-        Some(ss) => Ok(ss.update_global_mark(path, lineno, new_lineno, new_desc)),
+        Some(ss) => Ok(ss.discard_mark_shifts(path)),
         None => Ok(false),
     }
 }
 
 // Add local mark to the active stack
-pub fn add_local_mark(_: &Lua, (path, lineno, line): (String, i32, String)) -> LuaResult<bool> {
+pub fn add_local_mark(
+    _: &Lua,
+    (path, lineno, line, opts): (String, i32, String, marks::MarkOpts),
+) -> LuaResult<bool> {
     ::tracing::info!("Adding local mark: {}:{} - {}", path, lineno, line);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
-        Some(ss) => Ok(ss.add_local_mark(path, line, lineno)),
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.add_local_mark(path, line, lineno, opts)),
         None => Ok(false),
     }
 }
@@ -290,26 +716,49 @@ pub fn remove_local_mark(_: &Lua, (path, lineno): (String, i32)) -> LuaResult<bo
     ::tracing::info!("Removing global mark: {} at line {}", path, lineno);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
-    match sm.get_stacks_mut() {
+    match sm.get_stacks_writable()? {
         Some(ss) => Ok(ss.remove_local_mark(path, lineno)),
         None => Ok(false),
     }
 }
 
 // Return list of local marks in the active stack
-pub fn list_local_marks(_: &Lua, path: Option<String>) -> LuaResult<Vec<marks::LocalMark>> {
+pub fn list_local_marks(
+    _: &Lua,
+    (path, filter): (Option<String>, marks::MarkFilter),
+) -> LuaResult<Vec<marks::LocalMark>> {
     ::tracing::info!("Listing local marks...");
+    let path = path.map(|p| targets::normalize(&p));
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss
+            .list_local_marks()
+            .into_iter()
+            .filter(|m| path.as_ref().is_none_or(|p| m.path == *p) && m.matches(&filter))
+            .collect()),
+        None => Ok(vec![]),
+    }
+}
+
+// Records that a local mark of the active stack was jumped to
+pub fn visit_local_mark(_: &Lua, (path, lineno): (String, i32)) -> LuaResult<bool> {
+    ::tracing::debug!("Visiting local mark: {}:{}", path, lineno);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.visit_local_mark(path, lineno)),
+        None => Ok(false),
+    }
+}
+
+// Lists the local marks evicted from a stack, the active one by default
+pub fn list_evicted_marks(_: &Lua, name: Option<String>) -> LuaResult<Vec<marks::LocalMark>> {
+    ::tracing::info!("Listing evicted local marks of stack: {:?}", name);
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
     match sm.get_stacks() {
-        Some(ss) => match path {
-            Some(p) => Ok(ss
-                .list_local_marks()
-                .into_iter()
-                .filter(|m| m.path == p)
-                .collect()),
-            None => Ok(ss.list_local_marks()),
-        },
+        Some(ss) => Ok(ss.list_evicted_marks(name)),
         None => Ok(vec![]),
     }
 }
@@ -317,14 +766,141 @@ pub fn list_local_marks(_: &Lua, path: Option<String>) -> LuaResult<Vec<marks::L
 // Updates a local mark
 pub fn update_local_mark(
     _: &Lua,
-    (path, lineno, new_lineno): (String, i32, Option<i32>),
+    (path, lineno, new_lineno, opts): (String, i32, Option<i32>, marks::MarkOpts),
 ) -> LuaResult<bool> {
     ::tracing::info!("Updating local mark for path: {} at line: {}", path, lineno);
     ::tracing::info!("new_lineno={:?}", new_lineno);
     let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
     let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_writable()? {
+        Some(ss) => Ok(ss.update_local_mark(path, lineno, new_lineno, opts)),
+        None => Ok(false),
+    }
+}
+
+// Records activity on the active stack for time tracking
+pub fn heartbeat(_: &Lua, _: ()) -> LuaResult<bool> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.heartbeat()),
+        None => Ok(false),
+    }
+}
+
+// Closes the currently tracked time interval
+pub fn pause_tracking(_: &Lua, _: ()) -> LuaResult<bool> {
+    ::tracing::info!("Pausing time tracking...");
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.pause_tracking()),
+        None => Ok(false),
+    }
+}
+
+// Returns time spent per stack grouped by day, week or stack. The offset from UTC is either
+// fixed or a function returning it for a unix time, which must not call the backend.
+pub fn time_report(
+    _: &Lua,
+    (group_by, name, utc_offset): (String, Option<String>, LuaValue),
+) -> LuaResult<Vec<timetracking::TimeEntry>> {
+    ::tracing::info!("Time report grouped by {} for stack: {:?}", group_by, name);
+    let group_by = timetracking::GroupBy::parse(&group_by).ok_or(Errors::InvalidGroupBy(group_by))?;
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.time_report(group_by, name, |t| match &utc_offset {
+            LuaValue::Integer(o) => *o,
+            LuaValue::Number(o) => *o as i64,
+            LuaValue::Function(f) => f.call::<i64>(t).unwrap_or_else(|e| {
+                ::tracing::error!("Failed to get UTC offset: {}", e);
+                0
+            }),
+            _ => 0,
+        })),
+        None => Ok(vec![]),
+    }
+}
+
+// Returns all stack templates keyed by name
+pub fn list_templates(_: &Lua, _: ()) -> LuaResult<HashMap<String, templates::StackTemplate>> {
+    let stacks_man = STACKS.read().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_ref(), || Errors::StacksNotInit)?;
+    Ok(sm.list_templates()?)
+}
+
+// Creates a new stack from a template, expanding placeholders with vars
+pub fn create_stack_from_template(
+    _: &Lua,
+    (template, vars): (String, Option<HashMap<String, String>>),
+) -> LuaResult<Option<Stack>> {
+    ::tracing::info!("Creating stack from template {} with {:?}", template, vars);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    Ok(sm.create_stack_from_template(template, vars.unwrap_or_default())?)
+}
+
+// Saves a snapshot of a stack, or the active stack, and returns its id
+pub fn snapshot_stack(_: &Lua, name: Option<String>) -> LuaResult<Option<String>> {
+    ::tracing::info!("Snapshotting stack: {:?}", name);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.snapshot(name)?),
+        None => Ok(None),
+    }
+}
+
+// Lists snapshot ids, optionally for a single stack
+pub fn list_snapshots(_: &Lua, name: Option<String>) -> LuaResult<Vec<String>> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.list_snapshots(name)),
+        None => Ok(vec![]),
+    }
+}
+
+// Returns the pins and marks that changed going from stack a to stack b
+pub fn diff_stacks(_: &Lua, (a, b): (String, String)) -> LuaResult<Option<stackdiff::StackDiff>> {
+    ::tracing::info!("Diffing stacks {} and {}", a, b);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(Some(ss.diff(&a, &b)?)),
+        None => Ok(None),
+    }
+}
+
+// Returns the notes of a stack, or the active stack, with resolved mark references
+pub fn get_notes(_: &Lua, name: Option<String>) -> LuaResult<Option<notes::StackNotes>> {
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks() {
+        Some(ss) => Ok(ss.get_notes(name)?),
+        None => Ok(None),
+    }
+}
+
+// Replaces the notes of a stack, or the active stack
+pub fn set_notes(_: &Lua, (content, name): (String, Option<String>)) -> LuaResult<bool> {
+    ::tracing::info!("Setting notes for stack: {:?}", name);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
+    match sm.get_stacks_mut() {
+        Some(ss) => Ok(ss.set_notes(name, content)?),
+        None => Ok(false),
+    }
+}
+
+// Appends to the notes of a stack, or the active stack
+pub fn append_notes(_: &Lua, (content, name): (String, Option<String>)) -> LuaResult<bool> {
+    ::tracing::info!("Appending notes for stack: {:?}", name);
+    let mut stacks_man = STACKS.write().map_err(|_| Errors::AcquireStacksLock)?;
+    let sm = Option::ok_or_else(stacks_man.as_mut(), || Errors::StacksNotInit)?;
     match sm.get_stacks_mut() {
-        Some(ss) => Ok(ss.update_local_mark(path, lineno, new_lineno)),
+        Some(ss) => Ok(ss.append_notes(name, content)?),
         None => Ok(false),
     }
 }
@@ -337,6 +913,7 @@ fn codestacks_nvim(lua: &Lua) -> LuaResult<LuaTable> {
 
     exports.set("init_tracing", lua.create_function(init_tracing)?)?;
     exports.set("setup", lua.create_function(setup)?)?;
+    exports.set("flush", lua.create_function(flush)?)?;
 
     // Stack management functions
     exports.set("add_stack", lua.create_function(add_stack)?)?;
@@ -347,6 +924,20 @@ fn codestacks_nvim(lua: &Lua) -> LuaResult<LuaTable> {
     exports.set("rename_stack", lua.create_function(rename_stack)?)?;
     exports.set("get_active_stack", lua.create_function(get_active_stack)?)?;
     exports.set("get_stack", lua.create_function(get_stack)?)?;
+    exports.set("snapshot_stack", lua.create_function(snapshot_stack)?)?;
+    exports.set("list_snapshots", lua.create_function(list_snapshots)?)?;
+    exports.set("diff_stacks", lua.create_function(diff_stacks)?)?;
+    exports.set("list_templates", lua.create_function(list_templates)?)?;
+    exports.set(
+        "create_stack_from_template",
+        lua.create_function(create_stack_from_template)?,
+    )?;
+
+    exports.set("dedupe_paths", lua.create_function(dedupe_paths)?)?;
+    exports.set("rename_path", lua.create_function(rename_path)?)?;
+    exports.set("find_moved_path", lua.create_function(find_moved_path)?)?;
+    exports.set("gc", lua.create_function(gc)?)?;
+    exports.set("check_integrity", lua.create_function(check_integrity)?)?;
 
     // Recent files functions
     exports.set("add_recent_file", lua.create_function(add_recent_file)?)?;
@@ -357,8 +948,20 @@ fn codestacks_nvim(lua: &Lua) -> LuaResult<LuaTable> {
     // Buffer management functions
     exports.set("pin_buffer", lua.create_function(pin_buffer)?)?;
     exports.set("unpin_buffer", lua.create_function(unpin_buffer)?)?;
+    exports.set("pin_temp_buffer", lua.create_function(pin_temp_buffer)?)?;
+    exports.set("clear_temp_pins", lua.create_function(clear_temp_pins)?)?;
+    exports.set("next_free_label", lua.create_function(next_free_label)?)?;
+    exports.set("is_valid_label", lua.create_function(is_valid_label)?)?;
+    exports.set("insert_pin_at", lua.create_function(insert_pin_at)?)?;
+    exports.set("move_pin", lua.create_function(move_pin)?)?;
+    exports.set("swap_pins", lua.create_function(swap_pins)?)?;
+    exports.set("update_pin_cursor", lua.create_function(update_pin_cursor)?)?;
+    exports.set("set_pin_list", lua.create_function(set_pin_list)?)?;
+    exports.set("list_pin_lists", lua.create_function(list_pin_lists)?)?;
+    exports.set("remove_pin_list", lua.create_function(remove_pin_list)?)?;
     exports.set("list_pinned_buffers", lua.create_function(list_pinned_buffers)?)?;
     exports.set("get_pinned_buffer", lua.create_function(get_pinned_buffer)?)?;
+    exports.set("get_target", lua.create_function(get_target)?)?;
 
     // Mark management functions
     exports.set("add_global_mark", lua.create_function(add_global_mark)?)?;
@@ -369,11 +972,38 @@ fn codestacks_nvim(lua: &Lua) -> LuaResult<LuaTable> {
         lua.create_function(list_all_global_marks)?,
     )?;
     exports.set("update_global_mark", lua.create_function(update_global_mark)?)?;
+    exports.set("set_mark_key", lua.create_function(set_mark_key)?)?;
+    exports.set("get_mark_by_key", lua.create_function(get_mark_by_key)?)?;
+    exports.set("search", lua.create_function(search)?)?;
     // Local mark management functions
     exports.set("add_local_mark", lua.create_function(add_local_mark)?)?;
     exports.set("remove_local_mark", lua.create_function(remove_local_mark)?)?;
     exports.set("list_local_marks", lua.create_function(list_local_marks)?)?;
     exports.set("update_local_mark", lua.create_function(update_local_mark)?)?;
+    exports.set("visit_local_mark", lua.create_function(visit_local_mark)?)?;
+    exports.set("list_evicted_marks", lua.create_function(list_evicted_marks)?)?;
+    exports.set("locate_mark", lua.create_function(locate_mark)?)?;
+    exports.set("validate_marks", lua.create_function(validate_marks)?)?;
+    exports.set("next_mark", lua.create_function(next_mark)?)?;
+    exports.set("prev_mark", lua.create_function(prev_mark)?)?;
+    exports.set("export_quickfix", lua.create_function(export_quickfix)?)?;
+    exports.set("export_errorformat", lua.create_function(export_errorformat)?)?;
+    exports.set("import_quickfix", lua.create_function(import_quickfix)?)?;
+    exports.set("import_errorformat", lua.create_function(import_errorformat)?)?;
+    exports.set("has_marks", lua.create_function(has_marks)?)?;
+    exports.set("relocate_marks", lua.create_function(relocate_marks)?)?;
+    exports.set("shift_marks", lua.create_function(shift_marks)?)?;
+    exports.set("discard_mark_shifts", lua.create_function(discard_mark_shifts)?)?;
+
+    // Notes functions
+    exports.set("get_notes", lua.create_function(get_notes)?)?;
+    exports.set("set_notes", lua.create_function(set_notes)?)?;
+    exports.set("append_notes", lua.create_function(append_notes)?)?;
+
+    // Time tracking functions
+    exports.set("heartbeat", lua.create_function(heartbeat)?)?;
+    exports.set("pause_tracking", lua.create_function(pause_tracking)?)?;
+    exports.set("time_report", lua.create_function(time_report)?)?;
 
     Ok(exports)
 }
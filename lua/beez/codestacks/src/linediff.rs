@@ -0,0 +1,158 @@
+/// Edit distances above this are not worth diffing, marks fall back to their anchors instead.
+/// The trace kept for backtracking grows with its square, about 8MB at this distance.
+const MAX_EDIT_DISTANCE: usize = 1000;
+
+/// Maps every old line index to its index in new, None when the line was removed or changed.
+/// Returns None when the contents are too different to diff.
+pub fn map_lines(old: &[&str], new: &[&str]) -> Option<Vec<Option<usize>>> {
+    let mut map = vec![None; old.len()];
+    // Most edits leave the start and end of a file alone
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    for (i, m) in map.iter_mut().enumerate().take(prefix) {
+        *m = Some(i);
+    }
+    for k in 0..suffix {
+        map[old.len() - 1 - k] = Some(new.len() - 1 - k);
+    }
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+    for (i, j) in myers(a, b)? {
+        map[prefix + i] = Some(prefix + j);
+    }
+    Some(map)
+}
+
+// Returns the pairs of equal lines in a shortest edit script from a to b
+fn myers(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = a.len() + b.len();
+    if max == 0 {
+        return Some(Vec::new());
+    }
+    let offset = max as isize;
+    let mut v = vec![0isize; 2 * max + 2];
+    // Furthest reaching x per diagonal before each step d, only diagonals -d..=d are kept
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let at = |k: isize| (k + offset) as usize;
+
+    let mut found = false;
+    for d in 0..=(max.min(MAX_EDIT_DISTANCE) as isize) {
+        trace.push(v[at(-d)..=at(d)].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                found = true;
+                break;
+            }
+            k += 2;
+        }
+        if found {
+            break;
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        if d == 0 {
+            while x > 0 && y > 0 {
+                x -= 1;
+                y -= 1;
+                pairs.push((x as usize, y as usize));
+            }
+            break;
+        }
+        let get = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = get(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    pairs.reverse();
+    Some(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_inputs() {
+        assert_eq!(map_lines(&[], &[]), Some(vec![]));
+        assert_eq!(map_lines(&[], &["a"]), Some(vec![]));
+        assert_eq!(map_lines(&["a", "b"], &[]), Some(vec![None, None]));
+    }
+
+    #[test]
+    fn pure_insert() {
+        let map = map_lines(&["a", "b", "c"], &["a", "x", "y", "b", "c"]);
+        assert_eq!(map, Some(vec![Some(0), Some(3), Some(4)]));
+    }
+
+    #[test]
+    fn pure_delete() {
+        let map = map_lines(&["a", "b", "c", "d"], &["a", "d"]);
+        assert_eq!(map, Some(vec![Some(0), None, None, Some(1)]));
+    }
+
+    #[test]
+    fn replace_in_middle() {
+        let map = map_lines(&["a", "b", "c", "d", "e"], &["a", "b", "x", "y", "d", "e"]);
+        assert_eq!(map, Some(vec![Some(0), Some(1), None, Some(4), Some(5)]));
+    }
+
+    #[test]
+    fn common_prefix_and_suffix() {
+        let old = ["p", "q", "a", "b", "s", "t"];
+        let new = ["p", "q", "b", "x", "a", "s", "t"];
+        let map = map_lines(&old, &new).unwrap();
+        assert_eq!(&map[..2], &[Some(0), Some(1)]);
+        assert_eq!(&map[4..], &[Some(5), Some(6)]);
+        // Only one of the swapped lines can be kept
+        assert_eq!(map[2..4].iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn bails_out_above_max_edit_distance() {
+        let old: Vec<String> = (0..MAX_EDIT_DISTANCE).map(|i| format!("old {i}")).collect();
+        let new: Vec<String> = (0..MAX_EDIT_DISTANCE).map(|i| format!("new {i}")).collect();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new: Vec<&str> = new.iter().map(String::as_str).collect();
+        assert_eq!(map_lines(&old, &new), None);
+        // Just within the limit still diffs
+        let half = MAX_EDIT_DISTANCE / 2;
+        assert_eq!(map_lines(&old[..half], &new[..half]), Some(vec![None; half]));
+    }
+}
@@ -1,19 +1,249 @@
-use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use crate::anchors::Anchor;
+use crate::targets::{Target, TargetKind};
+use crate::timetracking;
+use mlua::{FromLua, IntoLua, Lua, Result as LuaResult, Value as LuaValue};
 use serde::{Deserialize, Serialize};
 use std::clone::Clone;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a stable id for a mark, used to reference marks from notes
+pub fn new_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    format!(
+        "{:x}",
+        nanos.wrapping_add(ID_COUNTER.fetch_add(1, Ordering::Relaxed))
+    )
+}
+
+/// Optional settings for a new mark passed from Lua
+#[derive(Clone, Debug, Default)]
+pub struct MarkOpts {
+    pub col: Option<i32>,
+    pub end_lineno: Option<i32>,
+    pub end_col: Option<i32>,
+    // Register to jump to a global mark with
+    pub key: Option<String>,
+    // An empty note clears the note of a mark
+    pub note: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub priority: Option<i32>,
+}
+
+impl MarkOpts {
+    // Whether a column or range is given
+    pub fn has_range(&self) -> bool {
+        self.col.is_some() || self.end_lineno.is_some() || self.end_col.is_some()
+    }
+}
+
+impl FromLua for MarkOpts {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Table(t) => t,
+            LuaValue::Nil => return Ok(MarkOpts::default()),
+            _ => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "MarkOpts".to_string(),
+                    message: Some("expected a table".to_string()),
+                });
+            }
+        };
+        Ok(MarkOpts {
+            col: table.get("col")?,
+            end_lineno: table.get("end_lineno")?,
+            end_col: table.get("end_col")?,
+            key: table.get("key")?,
+            note: table.get("note")?,
+            tags: table.get("tags")?,
+            priority: table.get("priority")?,
+        })
+    }
+}
+
+/// Which marks to list, all given conditions have to match
+#[derive(Clone, Debug, Default)]
+pub struct MarkFilter {
+    // Tags a mark must all have
+    pub tags: Vec<String>,
+    pub min_priority: Option<i32>,
+    pub has_note: Option<bool>,
+}
+
+impl FromLua for MarkFilter {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Table(t) => t,
+            LuaValue::Nil => return Ok(MarkFilter::default()),
+            _ => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "MarkFilter".to_string(),
+                    message: Some("expected a table".to_string()),
+                });
+            }
+        };
+        Ok(MarkFilter {
+            tags: table.get::<Option<Vec<String>>>("tags")?.unwrap_or_default(),
+            min_priority: table.get("min_priority")?,
+            has_note: table.get("has_note")?,
+        })
+    }
+}
+
+impl MarkFilter {
+    pub fn matches(&self, note: &Option<String>, tags: &[String], priority: i32) -> bool {
+        self.tags.iter().all(|t| tags.contains(t))
+            && self.min_priority.is_none_or(|p| priority >= p)
+            && self.has_note.is_none_or(|h| h == note.is_some())
+    }
+}
+
+// Trims, sorts and dedupes tags, dropping empty ones
+fn tag_set(tags: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Applies the note, tags and priority of opts, returns whether anything changed
+fn annotate(
+    note: &mut Option<String>,
+    tags: &mut Vec<String>,
+    priority: &mut i32,
+    opts: &MarkOpts,
+) -> bool {
+    let mut changed = false;
+    if let Some(n) = &opts.note {
+        let n = Some(n.clone()).filter(|n| !n.trim().is_empty());
+        changed |= *note != n;
+        *note = n;
+    }
+    if let Some(t) = &opts.tags {
+        let t = tag_set(t);
+        changed |= *tags != t;
+        *tags = t;
+    }
+    if let Some(p) = opts.priority {
+        changed |= *priority != p;
+        *priority = p;
+    }
+    changed
+}
+
+/// Whether a key can be used as a mark register, a single letter A-Z like vim file marks
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_ascii_uppercase()) && chars.next().is_none()
+}
+
+// Drops an end that lies before the start of the mark
+fn valid_end(
+    lineno: i32,
+    col: Option<i32>,
+    end_lineno: Option<i32>,
+    end_col: Option<i32>,
+) -> Option<i32> {
+    let end = end_lineno?;
+    let before_start = end < lineno || (end == lineno && end_col.zip(col).is_some_and(|(e, c)| e < c));
+    (!before_start).then_some(end)
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LocalMark {
+    #[serde(default)]
+    pub id: String,
     pub path: String,
+    #[serde(default)]
+    pub kind: TargetKind,
     pub line: String,
     pub lineno: i32,
+    // Unix time the mark was added or last jumped to
+    #[serde(default)]
+    pub visited_at: i64,
+    // 0-based column the mark starts at
+    #[serde(default)]
+    pub col: Option<i32>,
+    // End of the marked range, inclusive
+    #[serde(default)]
+    pub end_lineno: Option<i32>,
+    #[serde(default)]
+    pub end_col: Option<i32>,
+    // Multi-line note about the mark
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Higher is more important
+    #[serde(default)]
+    pub priority: i32,
+    // Context used to find the line again after edits
+    #[serde(default)]
+    pub anchor: Option<Anchor>,
+}
+
+impl LocalMark {
+    pub fn new(path: String, line: String, lineno: i32, anchor: Option<Anchor>) -> Self {
+        let target = Target::parse(&path);
+        LocalMark {
+            id: new_id(),
+            path: target.path,
+            kind: target.kind,
+            line,
+            lineno,
+            visited_at: timetracking::now(),
+            col: None,
+            end_lineno: None,
+            end_col: None,
+            note: None,
+            tags: Vec::new(),
+            priority: 0,
+            anchor,
+        }
+    }
+
+    // Sets the column and range of the mark
+    pub fn set_range(&mut self, opts: &MarkOpts) {
+        self.col = opts.col;
+        self.end_lineno = valid_end(self.lineno, opts.col, opts.end_lineno, opts.end_col);
+        self.end_col = self.end_lineno.and(opts.end_col);
+    }
+
+    // Sets the note, tags and priority given in opts, returns whether anything changed
+    pub fn annotate(&mut self, opts: &MarkOpts) -> bool {
+        annotate(&mut self.note, &mut self.tags, &mut self.priority, opts)
+    }
+
+    pub fn matches(&self, filter: &MarkFilter) -> bool {
+        filter.matches(&self.note, &self.tags, self.priority)
+    }
 }
 
 impl IntoLua for LocalMark {
     fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
         let table = lua.create_table()?;
+        table.set("id", self.id)?;
         table.set("path", self.path)?;
+        table.set("kind", self.kind)?;
         table.set("lineno", self.lineno)?;
+        table.set("col", self.col)?;
+        table.set("end_lineno", self.end_lineno)?;
+        table.set("end_col", self.end_col)?;
+        table.set("note", self.note)?;
+        table.set("tags", self.tags)?;
+        table.set("priority", self.priority)?;
+        table.set("visited_at", self.visited_at)?;
         table.set("line", self.line)?;
         Ok(LuaValue::Table(table))
     }
@@ -21,21 +251,125 @@ impl IntoLua for LocalMark {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GlobalMark {
+    #[serde(default)]
+    pub id: String,
     pub stack: String,
     pub path: String,
+    #[serde(default)]
+    pub kind: TargetKind,
     pub line: String,
     pub lineno: i32,
     pub desc: String,
+    // Register to jump to the mark with, unique within the stack
+    #[serde(default)]
+    pub key: Option<String>,
+    // 0-based column the mark starts at
+    #[serde(default)]
+    pub col: Option<i32>,
+    // End of the marked range, inclusive
+    #[serde(default)]
+    pub end_lineno: Option<i32>,
+    #[serde(default)]
+    pub end_col: Option<i32>,
+    // Multi-line note about the mark
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Higher is more important
+    #[serde(default)]
+    pub priority: i32,
+    // Context used to find the line again after edits
+    #[serde(default)]
+    pub anchor: Option<Anchor>,
+}
+
+impl GlobalMark {
+    pub fn new(
+        stack: String,
+        path: String,
+        desc: String,
+        line: String,
+        lineno: i32,
+        anchor: Option<Anchor>,
+    ) -> Self {
+        let target = Target::parse(&path);
+        GlobalMark {
+            id: new_id(),
+            stack,
+            path: target.path,
+            kind: target.kind,
+            line,
+            lineno,
+            desc,
+            key: None,
+            col: None,
+            end_lineno: None,
+            end_col: None,
+            note: None,
+            tags: Vec::new(),
+            priority: 0,
+            anchor,
+        }
+    }
+
+    // Sets the column and range of the mark
+    pub fn set_range(&mut self, opts: &MarkOpts) {
+        self.col = opts.col;
+        self.end_lineno = valid_end(self.lineno, opts.col, opts.end_lineno, opts.end_col);
+        self.end_col = self.end_lineno.and(opts.end_col);
+    }
+
+    // Sets the note, tags and priority given in opts, returns whether anything changed
+    pub fn annotate(&mut self, opts: &MarkOpts) -> bool {
+        annotate(&mut self.note, &mut self.tags, &mut self.priority, opts)
+    }
+
+    pub fn matches(&self, filter: &MarkFilter) -> bool {
+        filter.matches(&self.note, &self.tags, self.priority)
+    }
 }
 
 impl IntoLua for GlobalMark {
     fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
         let table = lua.create_table()?;
+        table.set("id", self.id)?;
         table.set("path", self.path)?;
+        table.set("kind", self.kind)?;
         table.set("line", self.line)?;
         table.set("desc", self.desc)?;
+        table.set("key", self.key)?;
         table.set("lineno", self.lineno)?;
+        table.set("col", self.col)?;
+        table.set("end_lineno", self.end_lineno)?;
+        table.set("end_col", self.end_col)?;
+        table.set("note", self.note)?;
+        table.set("tags", self.tags)?;
+        table.set("priority", self.priority)?;
         table.set("stack", self.stack)?;
         Ok(LuaValue::Table(table))
     }
 }
+
+/// Position of a local or global mark, used to walk marks in file and line order
+#[derive(Clone, Debug)]
+pub struct MarkPos {
+    // Either local or global
+    pub kind: &'static str,
+    pub id: String,
+    pub path: String,
+    pub lineno: i32,
+    pub col: Option<i32>,
+}
+
+impl IntoLua for MarkPos {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("kind", self.kind)?;
+        table.set("id", self.id)?;
+        table.set("path", self.path)?;
+        table.set("lineno", self.lineno)?;
+        table.set("col", self.col)?;
+        Ok(LuaValue::Table(table))
+    }
+}
@@ -0,0 +1,214 @@
+use crate::errors::Errors;
+use crate::marks::{GlobalMark, LocalMark};
+use crate::paths;
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+const REF_PREFIX: &str = "[[mark:";
+const REF_SUFFIX: &str = "]]";
+
+/// A reference from notes to a mark, resolved by mark id
+#[derive(Clone, Debug)]
+pub struct NoteRef {
+    pub id: String,
+    pub found: bool,
+    pub kind: Option<String>,
+    pub stack: Option<String>,
+    pub path: Option<String>,
+    pub lineno: Option<i32>,
+    pub line: Option<String>,
+    pub desc: Option<String>,
+}
+
+impl NoteRef {
+    fn missing(id: String) -> Self {
+        NoteRef {
+            id,
+            found: false,
+            kind: None,
+            stack: None,
+            path: None,
+            lineno: None,
+            line: None,
+            desc: None,
+        }
+    }
+
+    fn from_local(stack: &str, m: &LocalMark) -> Self {
+        NoteRef {
+            id: m.id.clone(),
+            found: true,
+            kind: Some("local".to_string()),
+            stack: Some(stack.to_string()),
+            path: Some(m.path.clone()),
+            lineno: Some(m.lineno),
+            line: Some(m.line.clone()),
+            desc: None,
+        }
+    }
+
+    fn from_global(m: &GlobalMark) -> Self {
+        NoteRef {
+            id: m.id.clone(),
+            found: true,
+            kind: Some("global".to_string()),
+            stack: Some(m.stack.clone()),
+            path: Some(m.path.clone()),
+            lineno: Some(m.lineno),
+            line: Some(m.line.clone()),
+            desc: Some(m.desc.clone()),
+        }
+    }
+}
+
+impl IntoLua for NoteRef {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("found", self.found)?;
+        table.set("kind", self.kind)?;
+        table.set("stack", self.stack)?;
+        table.set("path", self.path)?;
+        table.set("lineno", self.lineno)?;
+        table.set("line", self.line)?;
+        table.set("desc", self.desc)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Notes of a stack along with its resolved mark references
+pub struct StackNotes {
+    pub stack: String,
+    pub content: String,
+    pub refs: Vec<NoteRef>,
+}
+
+impl IntoLua for StackNotes {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("stack", self.stack)?;
+        table.set("content", self.content)?;
+        table.set("refs", self.refs)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Returns the ids of all [[mark:<id>]] references in the content, in order of appearance
+pub fn parse_refs(content: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(REF_PREFIX) {
+        rest = &rest[start + REF_PREFIX.len()..];
+        let end = match rest.find(REF_SUFFIX) {
+            Some(end) => end,
+            None => break,
+        };
+        let id = rest[..end].trim().to_string();
+        if !id.is_empty() && !ids.contains(&id) {
+            ids.push(id);
+        }
+        rest = &rest[end + REF_SUFFIX.len()..];
+    }
+    ids
+}
+
+/// Resolves mark ids against the given local and global marks
+pub fn resolve_refs<'a>(
+    ids: Vec<String>,
+    local_marks: impl Iterator<Item = (&'a str, &'a LocalMark)> + Clone,
+    global_marks: impl Iterator<Item = &'a GlobalMark> + Clone,
+) -> Vec<NoteRef> {
+    ids.into_iter()
+        .map(|id| {
+            if let Some((stack, m)) = local_marks.clone().find(|(_, m)| m.id == id) {
+                return NoteRef::from_local(stack, m);
+            }
+            if let Some(m) = global_marks.clone().find(|m| m.id == id) {
+                return NoteRef::from_global(m);
+            }
+            NoteRef::missing(id)
+        })
+        .collect()
+}
+
+/// Markdown notes stored as one file per stack
+#[derive(Clone)]
+pub struct Notes {
+    dir: PathBuf,
+}
+
+impl Notes {
+    pub fn new(dir: PathBuf) -> Self {
+        Notes { dir }
+    }
+
+    // Path to the notes file of a stack. Path separators are percent-encoded, along with % so
+    // the encoding stays reversible.
+    pub fn path(&self, stack: &str) -> PathBuf {
+        self.dir.join(format!("{}.md", paths::encode_file_name(stack)))
+    }
+
+    // Moves notes of a stack with path separators or % in its name from where they were stored
+    // before the name was encoded. Skipped when another stack owns the old file name.
+    pub fn migrate(&self, stack: &str, stacks: &[&String]) -> Result<(), Errors> {
+        if !stack.contains(['/', '\\', '%']) {
+            return Ok(());
+        }
+        let old_name = stack.replace(['/', '\\'], "_");
+        let old_path = self.dir.join(format!("{old_name}.md"));
+        let new_path = self.path(stack);
+        if !old_path.exists()
+            || new_path.exists()
+            || stacks.iter().any(|s| s.as_str() != stack && **s == old_name)
+        {
+            return Ok(());
+        }
+        fs::rename(old_path, new_path).map_err(Errors::Notes)
+    }
+
+    // Reads the notes of a stack, empty if there are none yet
+    pub fn read(&self, stack: &str) -> Result<String, Errors> {
+        let path = self.path(stack);
+        if !path.exists() {
+            return Ok(String::new());
+        }
+        fs::read_to_string(path).map_err(Errors::Notes)
+    }
+
+    // Replaces the notes of a stack
+    pub fn write(&self, stack: &str, content: &str) -> Result<(), Errors> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(stack), content).map_err(Errors::Notes)
+    }
+
+    // Appends to the notes of a stack
+    pub fn append(&self, stack: &str, content: &str) -> Result<(), Errors> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(stack))
+            .map_err(Errors::Notes)?;
+        file.write_all(content.as_bytes()).map_err(Errors::Notes)
+    }
+
+    // Moves notes along with a renamed stack
+    pub fn rename(&self, old_name: &str, new_name: &str) -> Result<(), Errors> {
+        let old_path = self.path(old_name);
+        if !old_path.exists() {
+            return Ok(());
+        }
+        fs::rename(old_path, self.path(new_name)).map_err(Errors::Notes)
+    }
+
+    // Deletes the notes of a removed stack
+    pub fn remove(&self, stack: &str) -> Result<(), Errors> {
+        let path = self.path(stack);
+        if !path.exists() {
+            return Ok(());
+        }
+        fs::remove_file(path).map_err(Errors::Notes)
+    }
+}
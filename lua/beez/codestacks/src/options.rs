@@ -0,0 +1,96 @@
+use crate::labels::{self, ConflictPolicy};
+use mlua::{FromLua, Lua, Result as LuaResult, Value as LuaValue};
+
+/// Backend options passed from the Lua config on setup
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Seconds without a heartbeat after which the active interval is cut off
+    pub idle_timeout: i64,
+    /// Labels that can be used for pinned buffers, in order of preference
+    pub labels: Vec<String>,
+    /// How to resolve pinning with a label that is already taken
+    pub conflict_policy: ConflictPolicy,
+    /// Labels used for temporary pins
+    pub temp_labels: Vec<String>,
+    /// Seconds after which temporary pins expire
+    pub temp_pin_ttl: Option<i64>,
+    /// Whether temporary pins are cleared when switching away from a stack
+    pub clear_temp_on_switch: bool,
+    /// Whether paths are normalized to the target of symlinks
+    pub resolve_symlinks: bool,
+    /// Minimum confidence for moving a mark whose line was edited
+    pub relocation_confidence: f64,
+    /// Most local marks kept per stack, the least recently visited are evicted first
+    pub max_local_marks: Option<usize>,
+    /// Most local marks kept per file in a stack
+    pub max_local_marks_per_file: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            idle_timeout: 300,
+            labels: labels::default_labels(),
+            conflict_policy: ConflictPolicy::Replace,
+            temp_labels: ('1'..='9').map(|c| c.to_string()).collect(),
+            temp_pin_ttl: None,
+            clear_temp_on_switch: false,
+            resolve_symlinks: false,
+            relocation_confidence: 0.7,
+            max_local_marks: None,
+            max_local_marks_per_file: None,
+        }
+    }
+}
+
+impl FromLua for Options {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let mut opts = Options::default();
+        let table = match value {
+            LuaValue::Table(t) => t,
+            LuaValue::Nil => return Ok(opts),
+            _ => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "Options".to_string(),
+                    message: Some("expected a table".to_string()),
+                });
+            }
+        };
+        if let Some(idle_timeout) = table.get::<Option<i64>>("idle_timeout")? {
+            opts.idle_timeout = idle_timeout;
+        }
+        if let Some(labels) = table.get::<Option<Vec<String>>>("labels")? {
+            opts.labels = labels;
+        }
+        if let Some(policy) = table.get::<Option<String>>("conflict_policy")? {
+            opts.conflict_policy =
+                ConflictPolicy::parse(&policy).ok_or_else(|| mlua::Error::FromLuaConversionError {
+                    from: "string",
+                    to: "ConflictPolicy".to_string(),
+                    message: Some(format!("unknown conflict policy {policy}")),
+                })?;
+        }
+        if let Some(temp_labels) = table.get::<Option<Vec<String>>>("temp_labels")? {
+            opts.temp_labels = temp_labels;
+        }
+        if let Some(ttl) = table.get::<Option<i64>>("temp_pin_ttl")? {
+            opts.temp_pin_ttl = Some(ttl);
+        }
+        if let Some(clear) = table.get::<Option<bool>>("clear_temp_on_switch")? {
+            opts.clear_temp_on_switch = clear;
+        }
+        if let Some(resolve) = table.get::<Option<bool>>("resolve_symlinks")? {
+            opts.resolve_symlinks = resolve;
+        }
+        if let Some(confidence) = table.get::<Option<f64>>("relocation_confidence")? {
+            opts.relocation_confidence = confidence;
+        }
+        // A limit of 0 keeps marks unbounded
+        opts.max_local_marks = table.get::<Option<usize>>("max_local_marks")?.filter(|m| *m > 0);
+        opts.max_local_marks_per_file = table
+            .get::<Option<usize>>("max_local_marks_per_file")?
+            .filter(|m| *m > 0);
+        Ok(opts)
+    }
+}
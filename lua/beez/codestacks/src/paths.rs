@@ -0,0 +1,148 @@
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RESOLVE_SYMLINKS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether normalized paths follow symlinks to their target
+pub fn set_resolve_symlinks(resolve: bool) {
+    RESOLVE_SYMLINKS.store(resolve, Ordering::Relaxed);
+}
+
+// Replaces a leading ~ with the home directory
+fn expand_home(path: &str) -> String {
+    let home = match env::var("HOME") {
+        Ok(h) if !h.is_empty() => h,
+        _ => return path.to_string(),
+    };
+    match path.strip_prefix('~') {
+        Some("") => home,
+        Some(rest) if rest.starts_with('/') => format!("{home}{rest}"),
+        _ => path.to_string(),
+    }
+}
+
+/// Makes a name usable as a file name by percent-encoding path separators, along with % so
+/// the encoding stays reversible
+pub fn encode_file_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for c in name.chars() {
+        match c {
+            '%' => encoded.push_str("%25"),
+            '/' => encoded.push_str("%2F"),
+            '\\' => encoded.push_str("%5C"),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+/// Reverses encode_file_name, unknown escapes are kept as they are
+pub fn decode_file_name(encoded: &str) -> String {
+    let mut name = String::new();
+    let mut rest = encoded;
+    while let Some(i) = rest.find('%') {
+        name.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let c = match rest.get(..3) {
+            Some("%25") => '%',
+            Some("%2F") => '/',
+            Some("%5C") => '\\',
+            _ => {
+                name.push('%');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        name.push(c);
+        rest = &rest[3..];
+    }
+    name.push_str(rest);
+    name
+}
+
+/// Collapses empty, "." and ".." segments and trailing slashes without touching the disk
+pub fn clean(path: &str) -> String {
+    let absolute = path.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => match parts.last() {
+                Some(&p) if p != ".." => {
+                    parts.pop();
+                }
+                // Cannot go above the root
+                _ if absolute => {}
+                _ => parts.push(part),
+            },
+            _ => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    match (absolute, joined.is_empty()) {
+        (true, _) => format!("/{joined}"),
+        (false, true) => ".".to_string(),
+        (false, false) => joined,
+    }
+}
+
+/// Canonical form of a local path: home expanded, relative to the current directory,
+/// without "." and ".." segments and optionally with symlinks resolved
+pub fn normalize(path: &str) -> String {
+    let expanded = expand_home(path);
+    let absolute = match (Path::new(&expanded).is_relative(), env::current_dir()) {
+        (true, Ok(cwd)) => format!("{}/{}", cwd.to_string_lossy(), expanded),
+        _ => expanded,
+    };
+    let cleaned = clean(&absolute);
+    if !RESOLVE_SYMLINKS.load(Ordering::Relaxed) {
+        return cleaned;
+    }
+    // Paths that do not exist yet can not be resolved
+    match fs::canonicalize(&cleaned) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(_) => cleaned,
+    }
+}
+
+/// Number of entries rewritten or merged when deduplicating paths
+#[derive(Clone, Debug, Default)]
+pub struct DedupeReport {
+    // Paths that changed when normalized
+    pub rewritten: usize,
+    // Duplicates removed after normalizing
+    pub pins: usize,
+    pub local_marks: usize,
+    pub global_marks: usize,
+    pub recent_files: usize,
+}
+
+impl DedupeReport {
+    // Whether nothing was rewritten or removed
+    pub fn is_empty(&self) -> bool {
+        self.rewritten + self.pins + self.local_marks + self.global_marks + self.recent_files == 0
+    }
+
+    pub fn merge(&mut self, other: DedupeReport) {
+        self.rewritten += other.rewritten;
+        self.pins += other.pins;
+        self.local_marks += other.local_marks;
+        self.global_marks += other.global_marks;
+        self.recent_files += other.recent_files;
+    }
+}
+
+impl IntoLua for DedupeReport {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("rewritten", self.rewritten)?;
+        table.set("pins", self.pins)?;
+        table.set("local_marks", self.local_marks)?;
+        table.set("global_marks", self.global_marks)?;
+        table.set("recent_files", self.recent_files)?;
+        Ok(LuaValue::Table(table))
+    }
+}
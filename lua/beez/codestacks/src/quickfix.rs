@@ -0,0 +1,112 @@
+use mlua::{FromLua, IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+
+/// What to import quickfix items as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportKind {
+    Local,
+    Global,
+}
+
+impl ImportKind {
+    pub fn parse(s: &str) -> Option<ImportKind> {
+        match s {
+            "local" => Some(ImportKind::Local),
+            "global" => Some(ImportKind::Global),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of a quickfix list, lines and columns are 1-based like in vim
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QfItem {
+    pub filename: String,
+    pub lnum: i32,
+    pub col: i32,
+    pub end_lnum: Option<i32>,
+    pub end_col: Option<i32>,
+    pub text: String,
+}
+
+impl IntoLua for QfItem {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("filename", self.filename)?;
+        table.set("lnum", self.lnum)?;
+        table.set("col", self.col)?;
+        table.set("end_lnum", self.end_lnum)?;
+        table.set("end_col", self.end_col)?;
+        table.set("text", self.text)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl FromLua for QfItem {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Table(t) => t,
+            _ => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "QfItem".to_string(),
+                    message: Some("expected a table".to_string()),
+                });
+            }
+        };
+        Ok(QfItem {
+            filename: table.get("filename")?,
+            lnum: table.get::<Option<i32>>("lnum")?.unwrap_or(1),
+            col: table.get::<Option<i32>>("col")?.unwrap_or(0),
+            // Vim uses 0 for items without an end
+            end_lnum: table.get::<Option<i32>>("end_lnum")?.filter(|l| *l > 0),
+            end_col: table.get::<Option<i32>>("end_col")?.filter(|c| *c > 0),
+            text: table.get::<Option<String>>("text")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// Formats items as file:line:col: text lines that the default errorformat reads
+pub fn to_errorformat(items: &[QfItem]) -> String {
+    items
+        .iter()
+        .map(|i| {
+            // Multi-line texts would be read back as separate entries
+            let text = i.text.lines().next().unwrap_or_default();
+            format!("{}:{}:{}: {}\n", i.filename, i.lnum, i.col.max(1), text)
+        })
+        .collect()
+}
+
+/// Parses file:line:col: text and file:line: text lines, skipping lines in other formats
+pub fn parse_errorformat(text: &str) -> Vec<QfItem> {
+    text.lines().filter_map(parse_line).collect()
+}
+
+// Splits a leading number off s, returning it and the rest after the following colon
+fn number_field(s: &str) -> Option<(i32, &str)> {
+    let digits = s.bytes().take_while(|b| b.is_ascii_digit()).count();
+    let n = s[..digits].parse().ok()?;
+    let rest = s[digits..].strip_prefix(':')?;
+    Some((n, rest))
+}
+
+fn parse_line(line: &str) -> Option<QfItem> {
+    // The file name ends at the first colon followed by a line number, so names may contain
+    // colons themselves
+    let (filename, lnum, rest) = line
+        .match_indices(':')
+        .filter(|(i, _)| *i > 0)
+        .find_map(|(i, _)| number_field(&line[i + 1..]).map(|(n, rest)| (&line[..i], n, rest)))?;
+    let (col, rest) = match number_field(rest) {
+        Some((c, rest)) => (c, rest),
+        None => (0, rest),
+    };
+    Some(QfItem {
+        filename: filename.to_string(),
+        lnum,
+        col,
+        end_lnum: None,
+        end_col: None,
+        text: rest.trim().to_string(),
+    })
+}
@@ -0,0 +1,162 @@
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+// Directories that never contain the moved file
+const SKIP_DIRS: [&str; 3] = ["node_modules", "target", "__pycache__"];
+// Files larger than this are not read when searching
+const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_CANDIDATES: usize = 10;
+
+/// Returns the path with the old prefix replaced by the new one, if it is the old path or inside it
+pub fn rebase(path: &str, old: &str, new: &str) -> Option<String> {
+    if path == old {
+        return Some(new.to_string());
+    }
+    let rest = path.strip_prefix(old)?.strip_prefix('/')?;
+    Some(format!("{}/{}", new.trim_end_matches('/'), rest))
+}
+
+/// Number of references rewritten by a rename
+#[derive(Clone, Debug, Default)]
+pub struct RenameReport {
+    pub pins: usize,
+    pub local_marks: usize,
+    pub global_marks: usize,
+    pub recent_files: usize,
+}
+
+impl RenameReport {
+    pub fn merge(&mut self, other: RenameReport) {
+        self.pins += other.pins;
+        self.local_marks += other.local_marks;
+        self.global_marks += other.global_marks;
+        self.recent_files += other.recent_files;
+    }
+
+    // Whether nothing was rewritten
+    pub fn is_empty(&self) -> bool {
+        self.pins + self.local_marks + self.global_marks + self.recent_files == 0
+    }
+}
+
+impl IntoLua for RenameReport {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("pins", self.pins)?;
+        table.set("local_marks", self.local_marks)?;
+        table.set("global_marks", self.global_marks)?;
+        table.set("recent_files", self.recent_files)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// A file that is likely the new location of a missing file
+#[derive(Clone, Debug)]
+pub struct MoveCandidate {
+    pub path: String,
+    // Marked lines found in the file
+    pub matched: usize,
+    pub total: usize,
+    // Share of marked lines found, or 0 when matched on file name only
+    pub score: f64,
+}
+
+impl IntoLua for MoveCandidate {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("path", self.path)?;
+        table.set("matched", self.matched)?;
+        table.set("total", self.total)?;
+        table.set("score", self.score)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+// Hashes a line ignoring surrounding whitespace, so reindented lines still match
+fn hash_line(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.trim().hash(&mut hasher);
+    hasher.finish()
+}
+
+// Collects all regular files below a directory, skipping hidden and build directories
+fn walk(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            if !name.starts_with('.') && !SKIP_DIRS.contains(&name.as_str()) {
+                walk(&entry.path(), files);
+            }
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+}
+
+/// Searches below root for files containing the lines that were marked in a missing file.
+/// Without marked lines, files with the same name are returned instead.
+pub fn find_candidates(missing: &str, marked_lines: &[String], root: &Path) -> Vec<MoveCandidate> {
+    let wanted: HashSet<u64> = marked_lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| hash_line(l))
+        .collect();
+    let file_name = Path::new(missing).file_name();
+
+    let mut files = Vec::new();
+    walk(root, &mut files);
+    let mut candidates: Vec<MoveCandidate> = files
+        .into_iter()
+        .filter_map(|path| {
+            let same_name = path.file_name() == file_name;
+            let matched = if wanted.is_empty() {
+                0
+            } else {
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(u64::MAX);
+                if size > MAX_FILE_SIZE {
+                    return None;
+                }
+                // Binary files are skipped since they can not be read as a string
+                let contents = fs::read_to_string(&path).ok()?;
+                let found: HashSet<u64> = contents.lines().map(hash_line).collect();
+                wanted.intersection(&found).count()
+            };
+            if matched == 0 && !same_name {
+                return None;
+            }
+            Some(MoveCandidate {
+                path: path.to_string_lossy().to_string(),
+                matched,
+                total: wanted.len(),
+                score: match wanted.len() {
+                    0 => 0.0,
+                    n => matched as f64 / n as f64,
+                },
+            })
+        })
+        .collect();
+
+    // Files with the same name win ties
+    candidates.sort_by(|a, b| {
+        let a_name = Path::new(&a.path).file_name() == file_name;
+        let b_name = Path::new(&b.path).file_name() == file_name;
+        b.score
+            .total_cmp(&a.score)
+            .then(b_name.cmp(&a_name))
+            .then(a.path.cmp(&b.path))
+    });
+    candidates.truncate(MAX_CANDIDATES);
+    candidates
+}
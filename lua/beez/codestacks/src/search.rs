@@ -0,0 +1,316 @@
+use crate::stacks::Stack;
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use std::collections::{HashMap, HashSet};
+
+// Weights of the fields a term can match in, the description counts the most
+const DESC_WEIGHT: f64 = 1.0;
+const TAGS_WEIGHT: f64 = 0.9;
+const NOTE_WEIGHT: f64 = 0.8;
+const LINE_WEIGHT: f64 = 0.7;
+const PATH_WEIGHT: f64 = 0.6;
+
+// Bonuses of a fuzzy match per matched character
+const BOUNDARY_BONUS: f64 = 2.0;
+const CONSECUTIVE_BONUS: f64 = 1.5;
+// Penalty per skipped character inside a match
+const GAP_PENALTY: f64 = 0.2;
+
+/// A searchable text of a mark or pin
+#[derive(Clone, Debug, PartialEq)]
+struct Field {
+    name: &'static str,
+    text: String,
+    // Lowercased characters of text to match against
+    chars: Vec<char>,
+    weight: f64,
+}
+
+impl Field {
+    fn new(name: &'static str, text: &str, weight: f64) -> Self {
+        Field {
+            name,
+            text: text.to_string(),
+            chars: text.to_lowercase().chars().collect(),
+            weight,
+        }
+    }
+}
+
+/// A mark, pin or stack notes in the index
+#[derive(Clone, Debug, PartialEq)]
+struct Doc {
+    // One of pin, local_mark, global_mark or note
+    kind: &'static str,
+    // Mark id, the path of a pin or the stack of notes
+    id: String,
+    path: String,
+    lineno: Option<i32>,
+    fields: Vec<Field>,
+}
+
+// Builds the documents of all marks and pins of a stack
+fn stack_docs(stack: &Stack) -> Vec<Doc> {
+    let mut docs = Vec::new();
+    for m in stack.global_marks.values().flatten() {
+        let mut fields = vec![
+            Field::new("desc", &m.desc, DESC_WEIGHT),
+            Field::new("line", m.line.trim(), LINE_WEIGHT),
+            Field::new("path", &m.path, PATH_WEIGHT),
+        ];
+        if let Some(note) = &m.note {
+            fields.push(Field::new("note", note, NOTE_WEIGHT));
+        }
+        if !m.tags.is_empty() {
+            fields.push(Field::new("tags", &m.tags.join(" "), TAGS_WEIGHT));
+        }
+        docs.push(Doc {
+            kind: "global_mark",
+            id: m.id.clone(),
+            path: m.path.clone(),
+            lineno: Some(m.lineno),
+            fields,
+        });
+    }
+    for m in &stack.local_marks {
+        let mut fields = vec![
+            Field::new("line", m.line.trim(), LINE_WEIGHT),
+            Field::new("path", &m.path, PATH_WEIGHT),
+        ];
+        if let Some(note) = &m.note {
+            fields.push(Field::new("note", note, NOTE_WEIGHT));
+        }
+        if !m.tags.is_empty() {
+            fields.push(Field::new("tags", &m.tags.join(" "), TAGS_WEIGHT));
+        }
+        docs.push(Doc {
+            kind: "local_mark",
+            id: m.id.clone(),
+            path: m.path.clone(),
+            lineno: Some(m.lineno),
+            fields,
+        });
+    }
+    let pins = stack
+        .pinned_buffers
+        .iter()
+        .chain(stack.pin_lists.values().flatten());
+    // The same path can be pinned in several pin lists
+    let mut pinned: HashSet<&str> = HashSet::new();
+    for pb in pins {
+        if !pinned.insert(&pb.path) {
+            continue;
+        }
+        docs.push(Doc {
+            kind: "pin",
+            id: pb.path.clone(),
+            path: pb.path.clone(),
+            lineno: None,
+            fields: vec![Field::new("path", &pb.path, PATH_WEIGHT)],
+        });
+    }
+    docs
+}
+
+/// A mark or pin matching a search query
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub project: String,
+    pub stack: String,
+    pub kind: &'static str,
+    pub id: String,
+    pub path: String,
+    pub lineno: Option<i32>,
+    // Field that matched best and its text
+    pub field: &'static str,
+    pub text: String,
+    pub score: f64,
+}
+
+impl IntoLua for SearchHit {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("project", self.project)?;
+        table.set("stack", self.stack)?;
+        table.set("kind", self.kind)?;
+        table.set("id", self.id)?;
+        table.set("path", self.path)?;
+        table.set("lineno", self.lineno)?;
+        table.set("field", self.field)?;
+        table.set("text", self.text)?;
+        table.set("score", self.score)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Marks, pins and notes of all stacks of a project prepared for fuzzy search
+#[derive(Clone, Debug, Default)]
+pub struct SearchIndex {
+    stacks: HashMap<String, Vec<Doc>>,
+    // Notes document by stack
+    notes: HashMap<String, Doc>,
+}
+
+impl SearchIndex {
+    /// Rebuilds the documents of the changed stacks, indexing added stacks and dropping
+    /// removed ones. Other stacks are left alone.
+    pub fn sync(&mut self, stacks: &HashMap<String, Stack>, changed: &[&str]) {
+        self.stacks.retain(|name, _| stacks.contains_key(name));
+        self.notes.retain(|name, _| stacks.contains_key(name));
+        for (name, stack) in stacks {
+            if changed.contains(&name.as_str()) || !self.stacks.contains_key(name) {
+                ::tracing::debug!("Reindexing stack {}", name);
+                self.stacks.insert(name.clone(), stack_docs(stack));
+            }
+        }
+    }
+
+    /// Rebuilds the documents of all stacks
+    pub fn sync_all(&mut self, stacks: &HashMap<String, Stack>) {
+        let names: Vec<&str> = stacks.keys().map(String::as_str).collect();
+        self.sync(stacks, &names);
+    }
+
+    /// Replaces the notes document of a stack, empty notes are not indexed
+    pub fn set_notes(&mut self, stack: &str, path: &str, content: &str) {
+        if content.trim().is_empty() {
+            self.notes.remove(stack);
+            return;
+        }
+        self.notes.insert(
+            stack.to_string(),
+            Doc {
+                kind: "note",
+                id: stack.to_string(),
+                path: path.to_string(),
+                lineno: None,
+                fields: vec![Field::new("note", content, NOTE_WEIGHT)],
+            },
+        );
+    }
+
+    /// Returns the documents matching every term of the query, best first
+    pub fn search(&self, project: &str, query: &str) -> Vec<SearchHit> {
+        let terms: Vec<Vec<char>> = query
+            .split_whitespace()
+            .map(|t| t.to_lowercase().chars().collect())
+            .collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let mut hits = Vec::new();
+        let notes = self
+            .notes
+            .iter()
+            .map(|(stack, doc)| (stack, std::slice::from_ref(doc)));
+        let docs = self.stacks.iter().map(|(stack, docs)| (stack, docs.as_slice()));
+        for (stack, docs) in docs.chain(notes) {
+            for doc in docs {
+                if let Some((score, field)) = score_doc(doc, &terms) {
+                    hits.push(SearchHit {
+                        project: project.to_string(),
+                        stack: stack.clone(),
+                        kind: doc.kind,
+                        id: doc.id.clone(),
+                        path: doc.path.clone(),
+                        lineno: doc.lineno,
+                        field: field.name,
+                        text: field.text.clone(),
+                        score,
+                    });
+                }
+            }
+        }
+        sort_hits(&mut hits);
+        hits
+    }
+}
+
+/// Orders hits best first, ties by stack, path and line
+pub fn sort_hits(hits: &mut [SearchHit]) {
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.stack.cmp(&b.stack))
+            .then_with(|| a.path.cmp(&b.path))
+            .then_with(|| a.lineno.cmp(&b.lineno))
+    });
+}
+
+// Sums the best weighted field score of every term, None when a term matches no field.
+// Also returns the field that contributed the most.
+fn score_doc<'a>(doc: &'a Doc, terms: &[Vec<char>]) -> Option<(f64, &'a Field)> {
+    let mut total = 0.0;
+    let mut best: Option<(f64, &Field)> = None;
+    for term in terms {
+        let (score, field) = doc
+            .fields
+            .iter()
+            .filter_map(|f| fuzzy_score(term, &f.chars).map(|s| (s * f.weight, f)))
+            .max_by(|a, b| a.0.total_cmp(&b.0))?;
+        total += score;
+        if best.is_none_or(|(s, _)| score > s) {
+            best = Some((score, field));
+        }
+    }
+    best.map(|(_, field)| (total, field))
+}
+
+// Whether the character at i starts a word
+fn is_boundary(text: &[char], i: usize) -> bool {
+    i == 0 || !text[i - 1].is_alphanumeric()
+}
+
+/// Scores how well a lowercased term matches text as a subsequence, None when it does not.
+/// The shortest match ending at the first complete match is scored, rewarding matches at word
+/// starts and runs of consecutive characters.
+pub fn fuzzy_score(term: &[char], text: &[char]) -> Option<f64> {
+    if term.is_empty() {
+        return Some(0.0);
+    }
+    // Find where the first complete match ends
+    let mut t = 0;
+    let mut end = None;
+    for (i, c) in text.iter().enumerate() {
+        if *c == term[t] {
+            t += 1;
+            if t == term.len() {
+                end = Some(i);
+                break;
+            }
+        }
+    }
+    let end = end?;
+    // Walk back to the latest start of a match ending there
+    let mut t = term.len();
+    let mut start = end;
+    for i in (0..=end).rev() {
+        if text[i] == term[t - 1] {
+            t -= 1;
+            if t == 0 {
+                start = i;
+                break;
+            }
+        }
+    }
+
+    let mut score = 0.0;
+    let mut t = 0;
+    let mut prev: Option<usize> = None;
+    for i in start..=end {
+        if t == term.len() || text[i] != term[t] {
+            continue;
+        }
+        score += 1.0;
+        if is_boundary(text, i) {
+            score += BOUNDARY_BONUS;
+        }
+        match prev {
+            Some(p) if p + 1 == i => score += CONSECUTIVE_BONUS,
+            Some(p) => score -= GAP_PENALTY * (i - p - 1) as f64,
+            None => {}
+        }
+        prev = Some(i);
+        t += 1;
+    }
+    Some(score.max(0.1))
+}
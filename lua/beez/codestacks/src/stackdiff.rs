@@ -0,0 +1,120 @@
+use crate::buffers::PinnedBuffer;
+use crate::marks::{GlobalMark, LocalMark};
+use crate::stacks::Stack;
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+
+/// Items added, removed or changed between two versions of a list
+pub struct Changes<T> {
+    pub added: Vec<T>,
+    pub removed: Vec<T>,
+    pub changed: Vec<(T, T)>,
+}
+
+impl<T: IntoLua> IntoLua for Changes<T> {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("added", self.added)?;
+        table.set("removed", self.removed)?;
+        let changed = lua.create_table()?;
+        for (old, new) in self.changed {
+            let change = lua.create_table()?;
+            change.set("old", old)?;
+            change.set("new", new)?;
+            changed.push(change)?;
+        }
+        table.set("changed", changed)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Structural differences between two stacks
+pub struct StackDiff {
+    pub pins: Changes<PinnedBuffer>,
+    pub local_marks: Changes<LocalMark>,
+    pub global_marks: Changes<GlobalMark>,
+}
+
+impl IntoLua for StackDiff {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("pins", self.pins)?;
+        table.set("local_marks", self.local_marks)?;
+        table.set("global_marks", self.global_marks)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+type Key<'a, T> = &'a dyn Fn(&T) -> String;
+
+/// Pairs up old and new items, trying each key in order of preference.
+/// Pairs that differ according to `same` are reported as changed.
+fn compare<T: Clone>(
+    old: Vec<T>,
+    new: Vec<T>,
+    keys: &[Key<T>],
+    same: impl Fn(&T, &T) -> bool,
+) -> Changes<T> {
+    let mut old: Vec<Option<T>> = old.into_iter().map(Some).collect();
+    let mut new: Vec<Option<T>> = new.into_iter().map(Some).collect();
+    let mut changed = Vec::new();
+    for key in keys {
+        for o in old.iter_mut() {
+            let old_key = match o {
+                Some(item) => key(item),
+                None => continue,
+            };
+            let found = new
+                .iter_mut()
+                .find(|n| n.as_ref().is_some_and(|item| key(item) == old_key));
+            if let Some(n) = found {
+                let (old_item, new_item) = (o.take().unwrap(), n.take().unwrap());
+                if !same(&old_item, &new_item) {
+                    changed.push((old_item, new_item));
+                }
+            }
+        }
+    }
+    Changes {
+        added: new.into_iter().flatten().collect(),
+        removed: old.into_iter().flatten().collect(),
+        changed,
+    }
+}
+
+/// Computes the differences going from stack a to stack b
+pub fn diff(a: &Stack, b: &Stack) -> StackDiff {
+    let pins = compare(
+        a.pinned_buffers.clone(),
+        b.pinned_buffers.clone(),
+        &[&|p: &PinnedBuffer| p.path.clone()],
+        |o, n| o.label == n.label,
+    );
+
+    // Marks are matched by position first, then by line text to detect moves
+    let local_marks = compare(
+        a.local_marks.clone(),
+        b.local_marks.clone(),
+        &[
+            &|m: &LocalMark| format!("{}:{}", m.path, m.lineno),
+            &|m: &LocalMark| format!("{}:{}", m.path, m.line),
+        ],
+        |o, n| o.lineno == n.lineno && o.line == n.line,
+    );
+
+    let global_marks = compare(
+        a.list_global_marks(None),
+        b.list_global_marks(None),
+        &[
+            &|m: &GlobalMark| format!("{}:{}", m.path, m.lineno),
+            &|m: &GlobalMark| format!("{}:{}", m.path, m.desc),
+            &|m: &GlobalMark| format!("{}:{}", m.path, m.line),
+        ],
+        |o, n| o.lineno == n.lineno && o.line == n.line && o.desc == n.desc,
+    );
+
+    StackDiff {
+        pins,
+        local_marks,
+        global_marks,
+    }
+}
@@ -1,20 +1,71 @@
+use crate::anchors::{self, Anchor, MarkChange, MarkOutcome, MarkStatus, Relocation, RelocationReport};
 use crate::buffers::PinnedBuffer;
-use crate::marks::{GlobalMark, LocalMark};
+use crate::errors::Errors;
+use crate::gc::{DanglingRef, GcMode, GcReport};
+use crate::integrity::Issue;
+use crate::labels::{self, ConflictPolicy, PinOutcome, PinStatus};
+use crate::linediff;
+use crate::marks::{self, GlobalMark, LocalMark, MarkOpts, MarkPos};
+use crate::notes::{self, Notes, StackNotes};
+use crate::options::Options;
+use crate::paths::{self, DedupeReport};
+use crate::quickfix::{ImportKind, QfItem};
+use crate::renames::{self, MoveCandidate, RenameReport};
+use crate::search::{self, SearchHit, SearchIndex};
+use crate::stackdiff::{self, StackDiff};
+use crate::targets::{self, Target, TargetKind};
+use crate::templates::{StackTemplate, Templates};
+use crate::timetracking::{self, GroupBy, Interval, TimeEntry};
 use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Stack {
-    name: String,
-    pinned_buffers: Vec<PinnedBuffer>,
-    local_marks: Vec<LocalMark>,
-    global_marks: HashMap<String, Vec<GlobalMark>>,
+    pub name: String,
+    pub pinned_buffers: Vec<PinnedBuffer>,
+    pub local_marks: Vec<LocalMark>,
+    pub global_marks: HashMap<String, Vec<GlobalMark>>,
+    #[serde(default)]
+    pub intervals: Vec<Interval>,
+    // Name of the pin list currently in pinned_buffers
+    #[serde(default = "default_pin_list")]
+    pub pin_list: String,
+    // Pin lists that are not currently visible
+    #[serde(default)]
+    pub pin_lists: HashMap<String, Vec<PinnedBuffer>>,
+    // Local marks dropped to stay within the limits, most recently evicted last
+    #[serde(default)]
+    pub evicted_local_marks: Vec<LocalMark>,
+}
+
+/// Evicted local marks kept per stack
+const MAX_EVICTED_MARKS: usize = 100;
+
+fn default_pin_list() -> String {
+    "default".to_string()
+}
+
+/// Summary of a named pin list in a stack
+pub struct PinList {
+    pub name: String,
+    pub active: bool,
+    pub count: usize,
+}
+
+impl IntoLua for PinList {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("active", self.active)?;
+        table.set("count", self.count)?;
+        Ok(LuaValue::Table(table))
+    }
 }
 
 impl IntoLua for Stack {
@@ -22,6 +73,7 @@ impl IntoLua for Stack {
         let table = lua.create_table()?;
         table.set("name", self.name)?;
         table.set("pinned_buffers", self.pinned_buffers)?;
+        table.set("pin_list", self.pin_list)?;
         table.set("local_marks", self.local_marks)?;
         table.set("global_marks", self.global_marks)?;
         Ok(LuaValue::Table(table))
@@ -29,6 +81,423 @@ impl IntoLua for Stack {
 }
 
 impl Stack {
+    // Creates an empty stack
+    fn new(name: String) -> Self {
+        Stack {
+            name,
+            pinned_buffers: Vec::new(),
+            local_marks: Vec::new(),
+            global_marks: HashMap::new(),
+            intervals: Vec::new(),
+            pin_list: default_pin_list(),
+            pin_lists: HashMap::new(),
+            evicted_local_marks: Vec::new(),
+        }
+    }
+
+    // Drops the least recently visited local marks until there are at most max per file and
+    // max in total, returns the number of marks evicted
+    fn evict_local_marks(&mut self, max: Option<usize>, max_per_file: Option<usize>) -> usize {
+        // Marks visited at the same time are evicted in the order they were added
+        let lru = |marks: &[LocalMark], path: Option<&str>| {
+            marks
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| path.is_none_or(|p| m.path == p))
+                .min_by_key(|(_, m)| m.visited_at)
+                .map(|(i, _)| i)
+        };
+        let mut evicted = Vec::new();
+        if let Some(max) = max_per_file {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for m in &self.local_marks {
+                *counts.entry(m.path.clone()).or_default() += 1;
+            }
+            for (path, count) in counts {
+                for _ in max..count {
+                    if let Some(i) = lru(&self.local_marks, Some(&path)) {
+                        evicted.push(self.local_marks.remove(i));
+                    }
+                }
+            }
+        }
+        if let Some(max) = max {
+            while self.local_marks.len() > max {
+                match lru(&self.local_marks, None) {
+                    Some(i) => evicted.push(self.local_marks.remove(i)),
+                    None => break,
+                }
+            }
+        }
+        let count = evicted.len();
+        self.evicted_local_marks.extend(evicted);
+        let excess = self.evicted_local_marks.len().saturating_sub(MAX_EVICTED_MARKS);
+        self.evicted_local_marks.drain(..excess);
+        count
+    }
+
+    // Removes temporary pins from every pin list, returns the removed pins
+    fn clear_temp_pins(&mut self) -> Vec<PinnedBuffer> {
+        let mut removed = Vec::new();
+        for pins in std::iter::once(&mut self.pinned_buffers).chain(self.pin_lists.values_mut()) {
+            let (temp, kept): (Vec<PinnedBuffer>, Vec<PinnedBuffer>) =
+                std::mem::take(pins).into_iter().partition(|b| b.temp);
+            *pins = kept;
+            removed.extend(temp);
+        }
+        removed
+    }
+
+    // Makes another pin list visible, creating it if it does not exist
+    fn switch_pin_list(&mut self, name: String) -> bool {
+        if self.pin_list == name {
+            return false;
+        }
+        let pins = self.pin_lists.remove(&name).unwrap_or_default();
+        let previous = std::mem::replace(&mut self.pinned_buffers, pins);
+        let previous_name = std::mem::replace(&mut self.pin_list, name);
+        self.pin_lists.insert(previous_name, previous);
+        true
+    }
+
+    // Normalizes every path in the stack and merges entries that turn out to be the same
+    fn dedupe_paths(&mut self) -> DedupeReport {
+        let mut report = DedupeReport::default();
+        let mut rewrite = |path: &mut String| {
+            let normalized = targets::normalize(path);
+            if *path != normalized {
+                *path = normalized;
+                report.rewritten += 1;
+            }
+        };
+
+        let mut removed_pins = 0;
+        for pins in std::iter::once(&mut self.pinned_buffers).chain(self.pin_lists.values_mut()) {
+            let mut seen: HashSet<String> = HashSet::new();
+            let original_len = pins.len();
+            pins.retain_mut(|b| {
+                rewrite(&mut b.path);
+                seen.insert(b.path.clone())
+            });
+            removed_pins += original_len - pins.len();
+        }
+
+        let mut seen: HashSet<(String, i32)> = HashSet::new();
+        let original_len = self.local_marks.len();
+        self.local_marks.retain_mut(|m| {
+            rewrite(&mut m.path);
+            seen.insert((m.path.clone(), m.lineno))
+        });
+        let removed_local_marks = original_len - self.local_marks.len();
+
+        let mut global_marks: HashMap<String, Vec<GlobalMark>> = HashMap::new();
+        let mut removed_global_marks = 0;
+        for mut gm in std::mem::take(&mut self.global_marks).into_values().flatten() {
+            rewrite(&mut gm.path);
+            let marks = global_marks.entry(gm.path.clone()).or_default();
+            if marks.iter().any(|m| m.lineno == gm.lineno) {
+                removed_global_marks += 1;
+            } else {
+                marks.push(gm);
+            }
+        }
+        self.global_marks = global_marks;
+
+        report.pins = removed_pins;
+        report.local_marks = removed_local_marks;
+        report.global_marks = removed_global_marks;
+        report
+    }
+
+    // Points every reference to old, or to a path inside it, to new instead
+    fn rename_path(&mut self, old: &str, new: &str) -> RenameReport {
+        let mut report = RenameReport::default();
+        let rebase = |path: &mut String, kind: &mut TargetKind| -> bool {
+            match renames::rebase(path, old, new) {
+                Some(p) => {
+                    let target = Target::parse(&p);
+                    *path = target.path;
+                    *kind = target.kind;
+                    true
+                }
+                None => false,
+            }
+        };
+
+        for pb in self
+            .pinned_buffers
+            .iter_mut()
+            .chain(self.pin_lists.values_mut().flatten())
+        {
+            if rebase(&mut pb.path, &mut pb.kind) {
+                report.pins += 1;
+            }
+        }
+        for m in self.local_marks.iter_mut() {
+            if rebase(&mut m.path, &mut m.kind) {
+                report.local_marks += 1;
+            }
+        }
+
+        let mut global_marks: HashMap<String, Vec<GlobalMark>> = HashMap::new();
+        for mut gm in std::mem::take(&mut self.global_marks).into_values().flatten() {
+            if rebase(&mut gm.path, &mut gm.kind) {
+                report.global_marks += 1;
+            }
+            global_marks.entry(gm.path.clone()).or_default().push(gm);
+        }
+        self.global_marks = global_marks;
+        // The new path may already have been referenced
+        if !report.is_empty() {
+            self.dedupe_paths();
+        }
+        report
+    }
+
+    // Finds pins and marks whose targets are known not to exist. When pruning they are moved
+    // out of this stack into the returned stack.
+    fn collect_dangling(&mut self, prune: bool) -> (Vec<DanglingRef>, Stack) {
+        let missing = |path: &String, kind: TargetKind| {
+            let target = Target {
+                kind,
+                path: path.clone(),
+            };
+            target.exists() == Some(false)
+        };
+        let dangling = |kind: &'static str, path: &String| DanglingRef {
+            stack: self.name.clone(),
+            kind,
+            path: path.clone(),
+            label: None,
+            pin_list: None,
+            lineno: None,
+        };
+        let mut refs = Vec::new();
+        let mut removed = Stack::new(self.name.clone());
+
+        let lists =
+            std::iter::once((&self.pin_list, &mut self.pinned_buffers)).chain(self.pin_lists.iter_mut());
+        for (list, pins) in lists {
+            for pb in pins.iter().filter(|b| missing(&b.path, b.kind)) {
+                refs.push(DanglingRef {
+                    label: Some(pb.label.clone()),
+                    pin_list: Some(list.clone()),
+                    ..dangling("pin", &pb.path)
+                });
+            }
+            if prune {
+                let (gone, kept) = std::mem::take(pins)
+                    .into_iter()
+                    .partition(|b| missing(&b.path, b.kind));
+                *pins = kept;
+                removed.pin_lists.insert(list.clone(), gone);
+            }
+        }
+
+        for m in self.local_marks.iter().filter(|m| missing(&m.path, m.kind)) {
+            refs.push(DanglingRef {
+                lineno: Some(m.lineno),
+                ..dangling("local_mark", &m.path)
+            });
+        }
+        if prune {
+            let (gone, kept) = std::mem::take(&mut self.local_marks)
+                .into_iter()
+                .partition(|m| missing(&m.path, m.kind));
+            self.local_marks = kept;
+            removed.local_marks = gone;
+        }
+
+        for m in self
+            .global_marks
+            .values()
+            .flatten()
+            .filter(|m| missing(&m.path, m.kind))
+        {
+            refs.push(DanglingRef {
+                lineno: Some(m.lineno),
+                ..dangling("global_mark", &m.path)
+            });
+        }
+        if prune {
+            let paths: Vec<String> = self.global_marks.keys().cloned().collect();
+            for path in paths {
+                let marks = self.global_marks.remove(&path).unwrap_or_default();
+                let (gone, kept): (Vec<GlobalMark>, Vec<GlobalMark>) =
+                    marks.into_iter().partition(|m| missing(&m.path, m.kind));
+                if !kept.is_empty() {
+                    self.global_marks.insert(path.clone(), kept);
+                }
+                if !gone.is_empty() {
+                    removed.global_marks.insert(path, gone);
+                }
+            }
+        }
+        (refs, removed)
+    }
+
+    // Checks the stack stored under key for inconsistencies, fixing them when repairing
+    fn check(&mut self, project: &str, key: &str, labels: &[String], repair: bool) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let mut issue = |check: &'static str, message: String| {
+            let mut issue = Issue::new(Some(project), Some(key), check, message);
+            issue.repaired = repair;
+            issues.push(issue);
+        };
+
+        if self.name != key {
+            issue(
+                "stack_name",
+                format!("Stack is named {} but stored as {}", self.name, key),
+            );
+            if repair {
+                self.name = key.to_string();
+            }
+        }
+
+        let mut seen: HashSet<(String, i32)> = HashSet::new();
+        let mut deduped: Vec<LocalMark> = Vec::new();
+        for m in std::mem::take(&mut self.local_marks) {
+            if seen.insert((m.path.clone(), m.lineno)) {
+                deduped.push(m);
+                continue;
+            }
+            issue(
+                "duplicate_mark",
+                format!("Duplicate local mark at {}:{}", m.path, m.lineno),
+            );
+            if !repair {
+                deduped.push(m);
+            }
+        }
+        self.local_marks = deduped;
+
+        seen.clear();
+        let mut mark_keys: HashSet<String> = HashSet::new();
+        let mut global_marks: HashMap<String, Vec<GlobalMark>> = HashMap::new();
+        let mut keys: Vec<String> = self.global_marks.keys().cloned().collect();
+        keys.sort();
+        for path in keys {
+            for mut m in self.global_marks.remove(&path).unwrap_or_default() {
+                if m.stack != key {
+                    issue(
+                        "mark_stack",
+                        format!(
+                            "Global mark at {}:{} belongs to stack {}",
+                            m.path, m.lineno, m.stack
+                        ),
+                    );
+                    if repair {
+                        m.stack = key.to_string();
+                    }
+                }
+                let mut entry = path.clone();
+                if m.path != path {
+                    issue(
+                        "mark_path",
+                        format!("Global mark at {}:{} is stored under {}", m.path, m.lineno, path),
+                    );
+                    if repair {
+                        entry = m.path.clone();
+                    }
+                }
+                if !seen.insert((m.path.clone(), m.lineno)) {
+                    issue(
+                        "duplicate_mark",
+                        format!("Duplicate global mark at {}:{}", m.path, m.lineno),
+                    );
+                    if repair {
+                        continue;
+                    }
+                }
+                if let Some(k) = &m.key
+                    && !mark_keys.insert(k.clone())
+                {
+                    issue(
+                        "duplicate_mark_key",
+                        format!(
+                            "Key {} is used by more than one global mark, ie {}:{}",
+                            k, m.path, m.lineno
+                        ),
+                    );
+                    if repair {
+                        m.key = None;
+                    }
+                }
+                global_marks.entry(entry).or_default().push(m);
+            }
+        }
+        self.global_marks = global_marks;
+
+        let lists =
+            std::iter::once((&self.pin_list, &mut self.pinned_buffers)).chain(self.pin_lists.iter_mut());
+        for (list, pins) in lists {
+            let mut paths: HashSet<String> = HashSet::new();
+            let mut kept: Vec<PinnedBuffer> = Vec::new();
+            for mut pb in std::mem::take(pins) {
+                if !paths.insert(pb.path.clone()) {
+                    issue(
+                        "duplicate_pin",
+                        format!("{} is pinned more than once in pin list {}", pb.path, list),
+                    );
+                    if repair {
+                        continue;
+                    }
+                }
+                if kept.iter().any(|b| b.label == pb.label) {
+                    issue(
+                        "duplicate_label",
+                        format!("Label {} is used more than once in pin list {}", pb.label, list),
+                    );
+                    if repair {
+                        // Pins without a free label to move to are dropped
+                        match labels::next_free_label(&kept, labels) {
+                            Some(l) => pb.label = l,
+                            None => continue,
+                        }
+                    }
+                }
+                kept.push(pb);
+            }
+            *pins = kept;
+        }
+        issues
+    }
+
+    // Moves a mark key to the global mark at path and lineno, clearing it from any other mark.
+    // Without a key the key of the mark is cleared.
+    pub fn set_mark_key(&mut self, path: &str, lineno: i32, key: Option<String>) -> bool {
+        if !self
+            .global_marks
+            .get(path)
+            .is_some_and(|gm| gm.iter().any(|m| m.lineno == lineno))
+        {
+            return false;
+        }
+        if let Some(k) = &key {
+            for m in self.global_marks.values_mut().flatten() {
+                if m.key.as_ref() == Some(k) {
+                    m.key = None;
+                }
+            }
+        }
+        for m in self.global_marks.get_mut(path).into_iter().flatten() {
+            if m.lineno == lineno {
+                m.key = key.clone();
+            }
+        }
+        true
+    }
+
+    // Finds the global mark with the given key
+    pub fn get_mark_by_key(&self, key: &str) -> Option<GlobalMark> {
+        self.global_marks
+            .values()
+            .flatten()
+            .find(|m| m.key.as_deref() == Some(key))
+            .cloned()
+    }
+
     // Return list of global marks in this stack
     pub fn list_global_marks(&self, path: Option<String>) -> Vec<GlobalMark> {
         match path {
@@ -51,18 +520,25 @@ impl Stack {
 
 #[derive(Serialize, Deserialize)]
 struct StacksIn {
+    // Format of the file, used to migrate data saved by older versions once
+    #[serde(default)]
+    version: u32,
     active: Option<String>,
     stacks: HashMap<String, Stack>,
 }
 
+/// Version of the stacks file written by save, 1 has normalized paths
+const STACKS_VERSION: u32 = 1;
+
 pub struct StacksManager {
     pub active: Option<String>,
     projects: HashMap<String, Stacks>,
+    templates: Templates,
 }
 
 impl StacksManager {
     /// Initializes the StacksManager struct
-    pub fn new(project: String, base_dir: &str) -> Self {
+    pub fn new(project: String, base_dir: &str, options: Options) -> Self {
         let dir_path = Path::new(base_dir);
         if !dir_path.exists() {
             fs::create_dir_all(dir_path)
@@ -75,7 +551,7 @@ impl StacksManager {
             let path = entry.path();
             if path.is_dir() {
                 let project_name = path.file_name().unwrap().to_str().unwrap().to_string();
-                let stacks = Stacks::new(path.to_str().unwrap());
+                let stacks = Stacks::new(path.to_str().unwrap(), options.clone());
                 projects.insert(project_name, stacks);
             }
         }
@@ -83,6 +559,7 @@ impl StacksManager {
         StacksManager {
             active: Some(project.clone()),
             projects,
+            templates: Templates::new(dir_path),
         }
     }
 
@@ -100,22 +577,134 @@ impl StacksManager {
         }
     }
 
+    // Get current active stacks to change, failing while its file can not be parsed since
+    // changes would not be saved
+    pub fn get_stacks_writable(&mut self) -> Result<Option<&mut Stacks>, Errors> {
+        match self.get_stacks_mut() {
+            Some(ss) => match &ss.load_error {
+                Some(e) => Err(Errors::StacksUnparsable(
+                    ss.target_file.to_string_lossy().to_string(),
+                    e.clone(),
+                )),
+                None => Ok(Some(ss)),
+            },
+            None => Ok(None),
+        }
+    }
+
     // List all stacks across projects
     pub fn list_stacks(&self) -> Vec<Stacks> {
         self.projects.values().cloned().collect()
     }
+
+    // Normalizes the paths in every project and removes the duplicates this creates
+    pub fn dedupe_paths(&mut self) -> DedupeReport {
+        let mut report = DedupeReport::default();
+        for stacks in self.projects.values_mut() {
+            report.merge(stacks.dedupe_paths());
+        }
+        report
+    }
+
+    // Points every reference to old, or to a path inside it, to new in every project
+    pub fn rename_path(&mut self, old: &str, new: &str) -> RenameReport {
+        let mut report = RenameReport::default();
+        for stacks in self.projects.values_mut() {
+            report.merge(stacks.rename_path(old, new));
+        }
+        report
+    }
+
+    // Searches below root for the new location of a missing file using the lines marked in it
+    pub fn find_moved_path(&self, path: &str, root: &Path) -> Vec<MoveCandidate> {
+        let path = targets::normalize(path);
+        let lines: Vec<String> = self
+            .projects
+            .values()
+            .flat_map(|ss| ss.marked_lines(&path))
+            .collect();
+        renames::find_candidates(&path, &lines, root)
+    }
+
+    // Checks the data of every project and the templates for inconsistencies
+    pub fn check_integrity(&mut self, repair: bool) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        if let Err(e) = self.templates.load() {
+            issues.push(Issue::new(None, None, "parse_error", e.to_string()));
+        }
+        let mut projects: Vec<(&String, &mut Stacks)> = self.projects.iter_mut().collect();
+        projects.sort_by(|a, b| a.0.cmp(b.0));
+        for (project, stacks) in projects {
+            issues.extend(stacks.check_integrity(project, repair));
+        }
+        issues
+    }
+
+    /// Fuzzy searches marks and pins of the active project, or of all projects
+    pub fn search(&self, query: &str, all_projects: bool, limit: Option<usize>) -> Vec<SearchHit> {
+        let mut hits: Vec<SearchHit> = self
+            .projects
+            .iter()
+            .filter(|(name, _)| all_projects || self.active.as_ref() == Some(*name))
+            .flat_map(|(name, stacks)| stacks.search(name, query))
+            .collect();
+        search::sort_hits(&mut hits);
+        if let Some(limit) = limit {
+            hits.truncate(limit);
+        }
+        hits
+    }
+
+    // List all stack templates by name
+    pub fn list_templates(&self) -> Result<HashMap<String, StackTemplate>, Errors> {
+        self.templates.load()
+    }
+
+    // Creates a new stack in the active project from a template
+    pub fn create_stack_from_template(
+        &mut self,
+        template: String,
+        vars: HashMap<String, String>,
+    ) -> Result<Option<Stack>, Errors> {
+        let template = self.templates.get(&template)?.expand(&vars)?;
+        match self.get_stacks_writable()? {
+            Some(ss) => ss.add_from_template(template).map(Some),
+            None => Ok(None),
+        }
+    }
 }
 
+/// Seconds between saves when only extending the current time interval
+const HEARTBEAT_SAVE_INTERVAL: i64 = 60;
+
 #[derive(Clone)]
 pub struct Stacks {
     target_file: PathBuf,
     pub active: Option<String>,
     stacks: HashMap<String, Stack>,
+    notes: Notes,
+    options: Options,
+    // Whether the last interval of the active stack is still open
+    tracking: bool,
+    last_heartbeat_save: i64,
+    // Whether there are changes that have not been saved yet
+    dirty: bool,
+    // Why the target file could not be parsed, nothing is saved over it while set
+    load_error: Option<String>,
+    // Last contents seen of files with marks, to diff against on the next change
+    known_contents: HashMap<String, String>,
+    // Line numbers on disk of marks shifted by buffer edits that have not been written yet,
+    // by path and mark id
+    live_marks: HashMap<String, HashMap<String, (i32, Option<i32>)>>,
+    // Ids of live marks whose line was removed by the edits, by path
+    removed_marks: HashMap<String, HashSet<String>>,
+    // Marks and pins of all stacks for search, synced whenever the stacks change
+    index: SearchIndex,
 }
 
 impl Stacks {
     /// Initializes the Stacks struct
-    pub fn new(base_dir: &str) -> Self {
+    pub fn new(base_dir: &str, options: Options) -> Self {
         let dir_path = Path::new(base_dir);
         if !dir_path.exists() {
             fs::create_dir_all(dir_path)
@@ -125,28 +714,116 @@ impl Stacks {
         let target_file = dir_path.join("stacks.json");
         let mut active: Option<String> = None;
         let mut stacks: HashMap<String, Stack> = HashMap::new();
+        let mut load_error: Option<String> = None;
+        let mut version = STACKS_VERSION;
         if !target_file.exists() {
             fs::File::create(&target_file)
                 .unwrap_or_else(|_| panic!("Failed to create file: {:?}", target_file.to_str()));
 
-            let j = json!({"stacks": stacks});
+            let j = json!({"version": version, "stacks": stacks});
             fs::write(&target_file, j.to_string())
                 .unwrap_or_else(|_| panic!("Failed to write to file: {:?}", target_file.to_str()));
         } else {
             let file = fs::File::open(&target_file)
                 .unwrap_or_else(|_| panic!("Failed to open file: {:?}", target_file.to_str()));
             let reader = BufReader::new(file);
-            let parsed: StacksIn = serde_json::from_reader(reader)
-                .unwrap_or_else(|_| panic!("Failed to parse json file: {:?}", target_file.to_str()));
-            stacks = parsed.stacks;
-            active = parsed.active;
+            // A broken file is left alone until it is repaired with check_integrity
+            match serde_json::from_reader::<_, StacksIn>(reader) {
+                Ok(parsed) => {
+                    stacks = parsed.stacks;
+                    active = parsed.active;
+                    version = parsed.version;
+                }
+                Err(e) => {
+                    ::tracing::error!("Failed to parse json file {:?}: {}", target_file.to_str(), e);
+                    load_error = Some(e.to_string());
+                }
+            }
+        }
+
+        // Marks created before ids existed need one to be referenced from notes
+        let mut missing_ids = false;
+        for stack in stacks.values_mut() {
+            let local_marks = stack.local_marks.iter_mut();
+            let global_marks = stack.global_marks.values_mut().flatten();
+            for id in local_marks
+                .map(|m| &mut m.id)
+                .chain(global_marks.map(|m| &mut m.id))
+            {
+                if id.is_empty() {
+                    *id = marks::new_id();
+                    missing_ids = true;
+                }
+            }
         }
 
-        Stacks {
+        // Targets saved before kinds existed default to files
+        for stack in stacks.values_mut() {
+            let pins = stack
+                .pinned_buffers
+                .iter_mut()
+                .chain(stack.pin_lists.values_mut().flatten())
+                .map(|b| (&b.path, &mut b.kind));
+            let local_marks = stack.local_marks.iter_mut().map(|m| (&m.path, &mut m.kind));
+            let global_marks = stack
+                .global_marks
+                .values_mut()
+                .flatten()
+                .map(|m| (&m.path, &mut m.kind));
+            for (path, kind) in pins.chain(local_marks).chain(global_marks) {
+                if *kind == TargetKind::File {
+                    *kind = Target::parse(path).kind;
+                }
+            }
+        }
+
+        // Limits may have been lowered since the marks were added
+        let mut evicted = 0;
+        for stack in stacks.values_mut() {
+            evicted +=
+                stack.evict_local_marks(options.max_local_marks, options.max_local_marks_per_file);
+        }
+
+        let mut stacks = Stacks {
             target_file,
             active,
             stacks,
+            notes: Notes::new(dir_path.join("notes")),
+            options,
+            tracking: false,
+            last_heartbeat_save: 0,
+            dirty: false,
+            load_error,
+            known_contents: HashMap::new(),
+            live_marks: HashMap::new(),
+            removed_marks: HashMap::new(),
+            index: SearchIndex::default(),
+        };
+        let names: Vec<&String> = stacks.stacks.keys().collect();
+        for name in &names {
+            if let Err(e) = stacks.notes.migrate(name, &names) {
+                ::tracing::error!("Failed to move notes of stack {}: {}", name, e);
+            }
         }
+        stacks.index.sync_all(&stacks.stacks);
+        let names: Vec<String> = stacks.stacks.keys().cloned().collect();
+        for name in &names {
+            stacks.index_notes(name);
+        }
+        // Data saved before paths were normalized may contain duplicates, migrated only once
+        // since normalizing depends on the current directory
+        if version < 1 && stacks.load_error.is_none() {
+            let report = stacks.dedupe_paths();
+            ::tracing::info!(
+                "Migrated stacks file {:?}: {:?}",
+                stacks.target_file.to_str(),
+                report
+            );
+            stacks.save();
+        } else if missing_ids || evicted > 0 {
+            stacks.save();
+        }
+        stacks
     }
 
     /// Adds a new stack if it doesn't already exist and sets it as active
@@ -154,24 +831,84 @@ impl Stacks {
         if self.stacks.contains_key(&name) {
             return false;
         }
-        let stack = Stack {
-            name: name.to_string(),
-            pinned_buffers: Vec::new(),
-            local_marks: Vec::new(),
-            global_marks: HashMap::new(),
-        };
+        let stack = Stack::new(name.to_string());
         self.stacks.insert(name.to_string(), stack);
-        self.active = Some(name);
+        self.switch_to(name);
+        self.save();
+        true
+    }
+
+    /// Adds a new stack populated from an expanded template and sets it as active
+    pub fn add_from_template(&mut self, template: StackTemplate) -> Result<Stack, Errors> {
+        if self.stacks.contains_key(&template.stack) {
+            return Err(Errors::StackExists(template.stack));
+        }
+        let mut stack = Stack::new(template.stack.clone());
+        for p in template.pins {
+            stack.pinned_buffers.retain(|b| b.label != p.label);
+            stack.pinned_buffers.push(PinnedBuffer::new(p.path, p.label));
+        }
+        for m in template.local_marks {
+            let lines = read_lines(&m.path);
+            let (line, anchor) = (line_at(&lines, m.lineno), Anchor::capture(&lines, m.lineno));
+            stack
+                .local_marks
+                .push(LocalMark::new(m.path, line, m.lineno, anchor));
+        }
+        for m in template.global_marks {
+            let lines = read_lines(&m.path);
+            let (line, anchor) = (line_at(&lines, m.lineno), Anchor::capture(&lines, m.lineno));
+            let global_mark =
+                GlobalMark::new(template.stack.clone(), m.path, m.desc, line, m.lineno, anchor);
+            stack
+                .global_marks
+                .entry(global_mark.path.clone())
+                .or_default()
+                .push(global_mark);
+        }
+        self.stacks.insert(template.stack.clone(), stack.clone());
+        self.switch_to(template.stack);
+        self.save();
+        Ok(stack)
+    }
+
+    // Returns the active stack for modification
+    fn active_stack_mut(&mut self) -> Option<&mut Stack> {
+        let name = self.active.as_ref()?;
+        self.stacks.get_mut(name)
+    }
+
+    /// Saves the stacks if there are pending changes
+    pub fn flush(&mut self) -> bool {
+        if !self.dirty {
+            return false;
+        }
         self.save();
         true
     }
 
     /// Saves the current stacks to the target file
-    pub fn save(&self) {
+    pub fn save(&mut self) {
+        // Changes outside the active stack sync the index themselves
+        let active: Vec<&str> = self.active.as_deref().into_iter().collect();
+        self.index.sync(&self.stacks, &active);
+        if self.load_error.is_some() {
+            ::tracing::error!("Not saving over unparsable file: {:?}", self.target_file.to_str());
+            return;
+        }
+        self.dirty = false;
+        // Marks shifted by unwritten edits are saved where they are on disk
+        let mut stacks = self.stacks.clone();
+        for (path, on_disk) in &self.live_marks {
+            for stack in stacks.values_mut() {
+                restore_linenos(stack, path, on_disk);
+            }
+        }
         // Serialize stacks to a JSON string.
         let out = StacksIn {
+            version: STACKS_VERSION,
             active: self.active.clone(),
-            stacks: self.stacks.clone(),
+            stacks,
         };
         let j =
             serde_json::to_string(&out).unwrap_or_else(|_| panic!("Failed to serialize stacks to JSON"));
@@ -180,6 +917,218 @@ impl Stacks {
             .unwrap_or_else(|_| panic!("Failed to save to file: {:?}", self.target_file.to_str()));
     }
 
+    /// Normalizes the paths in all stacks and removes the duplicates this creates
+    pub fn dedupe_paths(&mut self) -> DedupeReport {
+        let mut report = DedupeReport::default();
+        for stack in self.stacks.values_mut() {
+            report.merge(stack.dedupe_paths());
+        }
+        if !report.is_empty() {
+            self.index.sync_all(&self.stacks);
+            self.save();
+        }
+        report
+    }
+
+    /// Points every reference to old, or to a path inside it, to new in all stacks
+    pub fn rename_path(&mut self, old: &str, new: &str) -> RenameReport {
+        let (old, new) = (targets::normalize(old), targets::normalize(new));
+        let mut report = RenameReport::default();
+        for stack in self.stacks.values_mut() {
+            report.merge(stack.rename_path(&old, &new));
+        }
+        if !report.is_empty() {
+            self.index.sync_all(&self.stacks);
+            self.save();
+        }
+        report
+    }
+
+    // Returns the text of all marked lines in a file across stacks
+    fn marked_lines(&self, path: &str) -> Vec<String> {
+        self.stacks
+            .values()
+            .flat_map(|s| {
+                let local_marks = s.local_marks.iter().filter(|m| m.path == path).map(|m| &m.line);
+                let global_marks = s.global_marks.get(path).into_iter().flatten().map(|m| &m.line);
+                local_marks.chain(global_marks).cloned()
+            })
+            .collect()
+    }
+
+    /// Reports pins and marks of files that no longer exist and optionally removes them.
+    /// The given dangling recent files are included in the report and archive.
+    pub fn gc(&mut self, mode: GcMode, recent_files: Vec<String>) -> Result<GcReport, Errors> {
+        let prune = mode != GcMode::DryRun;
+        let mut stacks = self.stacks.clone();
+        let mut report = GcReport {
+            recent_files,
+            pruned: prune,
+            ..Default::default()
+        };
+        let mut archived: HashMap<String, Stack> = HashMap::new();
+        for stack in stacks.values_mut() {
+            let (refs, removed) = stack.collect_dangling(prune);
+            if !refs.is_empty() {
+                archived.insert(stack.name.clone(), removed);
+            }
+            report.refs.extend(refs);
+        }
+        report
+            .refs
+            .sort_by(|a, b| (&a.stack, &a.path, a.lineno).cmp(&(&b.stack, &b.path, b.lineno)));
+        if !prune {
+            return Ok(report);
+        }
+
+        // Only prune once the archive has been written so nothing gets lost
+        if mode == GcMode::Archive && !(archived.is_empty() && report.recent_files.is_empty()) {
+            let dir = self.target_file.with_file_name("archive");
+            fs::create_dir_all(&dir)?;
+            let file = dir.join(format!("{}.json", unique_stamp(&dir, "gc", ".json")));
+            let j = json!({"stacks": archived, "recent_files": report.recent_files});
+            fs::write(&file, j.to_string()).map_err(Errors::WriteArchive)?;
+            report.archive = Some(file.to_string_lossy().to_string());
+        }
+        if !report.refs.is_empty() {
+            self.stacks = stacks;
+            self.index.sync_all(&self.stacks);
+            self.save();
+        }
+        Ok(report)
+    }
+
+    /// Checks the stacks of this project for inconsistencies, fixing them when repairing
+    pub fn check_integrity(&mut self, project: &str, repair: bool) -> Vec<Issue> {
+        let mut issues = Vec::new();
+
+        if let Some(e) = &self.load_error {
+            let mut issue = Issue::new(
+                Some(project),
+                None,
+                "parse_error",
+                format!("Failed to parse {}: {}", self.target_file.to_string_lossy(), e),
+            );
+            // The broken file is kept next to the new one so nothing is lost
+            if repair {
+                let stem = self.target_file.file_stem().unwrap_or_default().to_string_lossy();
+                let dir = self.target_file.parent().unwrap_or(Path::new(""));
+                let backup = dir.join(unique_stamp(dir, &format!("{stem}.json.corrupt"), ""));
+                match fs::rename(&self.target_file, &backup) {
+                    Ok(_) => {
+                        self.load_error = None;
+                        issue.repaired = true;
+                        issue.message =
+                            format!("{}, moved to {}", issue.message, backup.to_string_lossy());
+                    }
+                    Err(e) => ::tracing::error!("Failed to move unparsable file aside: {}", e),
+                }
+            }
+            issues.push(issue);
+        }
+
+        // Snapshots are only reported since they are never written to again
+        if let Ok(entries) = fs::read_dir(self.snapshots_dir()) {
+            let mut files: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            files.sort();
+            for file in files {
+                let parsed = fs::read_to_string(&file)
+                    .map_err(|e| e.to_string())
+                    .and_then(|c| serde_json::from_str::<Stack>(&c).map_err(|e| e.to_string()));
+                if let Err(e) = parsed {
+                    issues.push(Issue::new(
+                        Some(project),
+                        None,
+                        "parse_error",
+                        format!("Failed to parse snapshot {}: {}", file.to_string_lossy(), e),
+                    ));
+                }
+            }
+        }
+
+        if let Some(active) = &self.active
+            && !self.stacks.contains_key(active)
+        {
+            let mut issue = Issue::new(
+                Some(project),
+                Some(active),
+                "orphaned_active",
+                format!("Active stack {active} does not exist"),
+            );
+            issue.repaired = repair;
+            issues.push(issue);
+            if repair {
+                self.active = None;
+                self.tracking = false;
+            }
+        }
+
+        let labels: Vec<String> = self
+            .options
+            .labels
+            .iter()
+            .chain(self.options.temp_labels.iter())
+            .cloned()
+            .collect();
+        let mut keys: Vec<String> = self.stacks.keys().cloned().collect();
+        keys.sort();
+        for key in keys {
+            if let Some(stack) = self.stacks.get_mut(&key) {
+                issues.extend(stack.check(project, &key, &labels, repair));
+            }
+        }
+        issues.extend(self.check_evicted_refs(project));
+
+        if repair && issues.iter().any(|i| i.repaired) {
+            self.index.sync_all(&self.stacks);
+            self.save();
+        }
+        issues
+    }
+
+    // Reports references from notes to local marks that were evicted. These are left for the
+    // user to fix since the mark can be added again or the reference dropped.
+    fn check_evicted_refs(&self, project: &str) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let mut names: Vec<&String> = self.stacks.keys().collect();
+        names.sort();
+        for name in names {
+            let content = match self.notes.read(name) {
+                Ok(c) => c,
+                Err(e) => {
+                    ::tracing::error!("Failed to read notes of stack {}: {}", name, e);
+                    continue;
+                }
+            };
+            for id in notes::parse_refs(&content) {
+                let live = self.stacks.values().any(|s| {
+                    s.local_marks.iter().any(|m| m.id == id)
+                        || s.global_marks.values().flatten().any(|m| m.id == id)
+                });
+                if live {
+                    continue;
+                }
+                let evicted = self
+                    .stacks
+                    .values()
+                    .flat_map(|s| s.evicted_local_marks.iter().map(move |m| (&s.name, m)))
+                    .find(|(_, m)| m.id == id);
+                if let Some((stack, m)) = evicted {
+                    issues.push(Issue::new(
+                        Some(project),
+                        Some(name),
+                        "evicted_mark_ref",
+                        format!(
+                            "Notes of stack {name} reference local mark {id} at {}:{} evicted from stack {stack}",
+                            m.path, m.lineno
+                        ),
+                    ));
+                }
+            }
+        }
+        issues
+    }
+
     /// Returns a list of all stacks
     pub fn list(&self) -> Vec<Stack> {
         self.stacks.values().cloned().collect::<Vec<Stack>>()
@@ -190,11 +1139,28 @@ impl Stacks {
         if !self.stacks.contains_key(&name) {
             return false;
         }
-        self.active = Some(name);
+        if self.active.as_ref() != Some(&name) {
+            self.switch_to(name);
+        }
         self.save();
         true
     }
 
+    // Makes another stack active, moving time tracking over and clearing temporary pins of the
+    // previous stack when configured
+    fn switch_to(&mut self, name: String) {
+        self.stop_tracking();
+        if self.options.clear_temp_on_switch
+            && let Some(stack) = self.active_stack_mut()
+            && !stack.clear_temp_pins().is_empty()
+        {
+            let left = stack.name.clone();
+            self.index.sync(&self.stacks, &[&left]);
+        }
+        self.active = Some(name);
+        self.start_tracking();
+    }
+
     /// Checks if the given name is the active stack
     pub fn is_active(&self, name: String) -> bool {
         match &self.active {
@@ -219,10 +1185,14 @@ impl Stacks {
         let stack = self.get(Some(name.clone()));
         match stack {
             Some(s) => {
-                self.stacks.remove(name.as_str());
-                if self.active == Some(name) {
+                if self.active == Some(name.clone()) {
+                    self.stop_tracking();
                     self.active = None;
                 }
+                self.stacks.remove(name.as_str());
+                if let Err(e) = self.notes.remove(&name) {
+                    ::tracing::error!("Failed to remove notes of stack {}: {}", name, e);
+                }
                 self.save();
                 Some(s)
             }
@@ -237,7 +1207,14 @@ impl Stacks {
         }
         let mut stack = self.stacks.remove(&old_name).unwrap();
         stack.name = new_name.clone();
+        for gm in stack.global_marks.values_mut().flatten() {
+            gm.stack = new_name.clone();
+        }
         self.stacks.insert(new_name.clone(), stack);
+        if let Err(e) = self.notes.rename(&old_name, &new_name) {
+            ::tracing::error!("Failed to move notes of stack {}: {}", old_name, e);
+        }
+        self.index_notes(&new_name);
         if self.active == Some(old_name) {
             self.active = Some(new_name);
         }
@@ -245,42 +1222,253 @@ impl Stacks {
         true
     }
 
-    // Pins a buffer by path and a label to the active stack
-    pub fn pin_buffer(&mut self, path: String, label: String) -> bool {
-        let active_name = match &self.active {
-            Some(name) => name.clone(),
+    // Pins a buffer by path and a label to the active stack, without a label the next free
+    // label is used. Label conflicts are resolved with the policy or the configured default.
+    pub fn pin_buffer(
+        &mut self,
+        path: String,
+        label: Option<String>,
+        policy: Option<ConflictPolicy>,
+    ) -> Option<PinOutcome> {
+        let path = targets::normalize(&path);
+        let policy = policy.unwrap_or(self.options.conflict_policy);
+        let labels = self.options.labels.clone();
+        let stack = self.active_stack_mut()?;
+        let outcome = labels::pin(&mut stack.pinned_buffers, path, label, policy, &labels);
+        let mut save = outcome.status.changed();
+        // Pinning a temporary pin with a regular label makes it permanent
+        if let Some(pb) = stack
+            .pinned_buffers
+            .iter_mut()
+            .find(|b| b.path == outcome.path && b.temp)
+            && outcome.status != PinStatus::Rejected
+        {
+            pb.temp = false;
+            pb.expires_at = None;
+            save = true;
+        }
+        if save {
+            self.save();
+        }
+        Some(outcome)
+    }
+
+    // Pins a buffer to the active stack with the next free temporary label
+    pub fn pin_temp_buffer(&mut self, path: String, ttl: Option<i64>) -> Option<PinOutcome> {
+        let path = targets::normalize(&path);
+        let labels = self.options.temp_labels.clone();
+        let expires_at = ttl.or(self.options.temp_pin_ttl).map(|t| timetracking::now() + t);
+        let stack = self.active_stack_mut()?;
+        // Already pinned buffers keep their label
+        if let Some(pb) = stack.pinned_buffers.iter().find(|b| b.path == path) {
+            return Some(PinOutcome::new(
+                PinStatus::Unchanged,
+                path,
+                Some(pb.label.clone()),
+            ));
+        }
+        let outcome = labels::pin(
+            &mut stack.pinned_buffers,
+            path,
+            None,
+            ConflictPolicy::Reject,
+            &labels,
+        );
+        if !outcome.status.changed() {
+            return Some(outcome);
+        }
+        if let Some(pb) = stack.pinned_buffers.iter_mut().find(|b| b.path == outcome.path) {
+            pb.temp = true;
+            pb.expires_at = expires_at;
+        }
+        self.save();
+        Some(outcome)
+    }
+
+    // Removes all temporary pins from every pin list of the active stack, returns the removed pins
+    pub fn clear_temp_pins(&mut self) -> Vec<PinnedBuffer> {
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let removed = stack.clear_temp_pins();
+        if !removed.is_empty() {
+            self.save();
+        }
+        removed
+    }
+
+    // Removes temporary pins past their expiry from every pin list of the active stack
+    fn expire_temp_pins(&mut self) -> bool {
+        let now = timetracking::now();
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        let mut expired = false;
+        for pins in std::iter::once(&mut stack.pinned_buffers).chain(stack.pin_lists.values_mut()) {
+            let original_len = pins.len();
+            pins.retain(|b| !b.is_expired(now));
+            expired |= pins.len() != original_len;
+        }
+        expired
+    }
+
+    // Pins a buffer at a 1-based position in the active stack, shifting later pins back
+    pub fn insert_pin_at(
+        &mut self,
+        path: String,
+        label: Option<String>,
+        index: usize,
+        policy: Option<ConflictPolicy>,
+    ) -> Option<PinOutcome> {
+        let outcome = self.pin_buffer(path.clone(), label, policy)?;
+        if outcome.status.changed() || outcome.status == PinStatus::Unchanged {
+            self.move_pin(path, index);
+        }
+        Some(outcome)
+    }
+
+    // Makes a named pin list of the active stack visible
+    pub fn set_pin_list(&mut self, name: String) -> bool {
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        if !stack.switch_pin_list(name) {
+            return false;
+        }
+        self.save();
+        true
+    }
+
+    // Lists the pin lists of the active stack, the visible one first
+    pub fn list_pin_lists(&self) -> Vec<PinList> {
+        let stack = match self.get(None) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let mut lists = vec![PinList {
+            name: stack.pin_list.clone(),
+            active: true,
+            count: stack.pinned_buffers.len(),
+        }];
+        let mut others: Vec<PinList> = stack
+            .pin_lists
+            .iter()
+            .map(|(name, pins)| PinList {
+                name: name.clone(),
+                active: false,
+                count: pins.len(),
+            })
+            .collect();
+        others.sort_by(|a, b| a.name.cmp(&b.name));
+        lists.extend(others);
+        lists
+    }
+
+    // Removes a pin list that is not currently visible from the active stack
+    pub fn remove_pin_list(&mut self, name: String) -> bool {
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        if stack.pin_lists.remove(&name).is_none() {
+            return false;
+        }
+        self.save();
+        true
+    }
+
+    // Records the cursor position of a pinned buffer in the active stack. Only kept in memory
+    // until the next save since this is called often.
+    pub fn update_pin_cursor(&mut self, path: String, lnum: i32, col: i32) -> bool {
+        let path = targets::normalize(&path);
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
             None => return false,
         };
-        let stack = match self.stacks.get_mut(&active_name) {
+        let pb = match stack.pinned_buffers.iter_mut().find(|b| b.path == path) {
+            Some(pb) => pb,
+            None => return false,
+        };
+        if pb.lnum == Some(lnum) && pb.col == Some(col) {
+            return false;
+        }
+        pb.lnum = Some(lnum);
+        pb.col = Some(col);
+        self.dirty = true;
+        true
+    }
+
+    // Returns the first label not used by a pin in the active stack
+    pub fn next_free_label(&self, alphabet: Option<Vec<String>>) -> Option<String> {
+        let alphabet = alphabet.unwrap_or_else(|| self.options.labels.clone());
+        let stack = self.get(None)?;
+        labels::next_free_label(&stack.pinned_buffers, &alphabet)
+    }
+
+    // Checks whether a label is part of the configured label alphabet
+    pub fn is_valid_label(&self, label: String) -> bool {
+        self.options.labels.contains(&label)
+    }
+
+    // Moves a pinned buffer to a 1-based position in the active stack
+    pub fn move_pin(&mut self, path: String, index: usize) -> bool {
+        let path = targets::normalize(&path);
+        let stack = match self.active_stack_mut() {
             Some(s) => s,
             None => return false,
         };
-        // First remove any existing pinned buffer with the same path and label
-        stack
-            .pinned_buffers
-            .retain(|b| b.path != path && b.label != label);
+        let from = match stack.pinned_buffers.iter().position(|b| b.path == path) {
+            Some(i) => i,
+            None => return false,
+        };
+        let pb = stack.pinned_buffers.remove(from);
+        let index = index.saturating_sub(1).min(stack.pinned_buffers.len());
+        stack.pinned_buffers.insert(index, pb);
+        self.save();
+        true
+    }
 
-        let pb = PinnedBuffer { path, label };
-        stack.pinned_buffers.push(pb);
+    // Swaps the positions of two pinned buffers in the active stack
+    pub fn swap_pins(&mut self, a: String, b: String) -> bool {
+        let (a, b) = (targets::normalize(&a), targets::normalize(&b));
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        let a = stack.pinned_buffers.iter().position(|p| p.path == a);
+        let b = stack.pinned_buffers.iter().position(|p| p.path == b);
+        match (a, b) {
+            (Some(a), Some(b)) => stack.pinned_buffers.swap(a, b),
+            _ => return false,
+        }
         self.save();
         true
     }
 
     // Unpins a buffer by path from the active stack
     pub fn unpin_buffer(&mut self, path: String) -> bool {
+        let path = targets::normalize(&path);
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
         };
+        let temp_labels = self.options.temp_labels.clone();
         let stack = match self.stacks.get_mut(&active_name) {
             Some(s) => s,
             None => return false,
         };
         let original_len = stack.pinned_buffers.len();
+        let was_temp = stack.pinned_buffers.iter().any(|b| b.path == path && b.temp);
         stack.pinned_buffers.retain(|b| b.path != path);
         if stack.pinned_buffers.len() == original_len {
             return false;
         }
+        if was_temp {
+            labels::compact_temp_labels(&mut stack.pinned_buffers, &temp_labels);
+        }
         self.save();
         true
     }
@@ -291,17 +1479,32 @@ impl Stacks {
             Some(s) => s,
             None => return Vec::new(),
         };
-        stack.pinned_buffers
+        // Expired pins are only removed on the next heartbeat
+        let now = timetracking::now();
+        stack
+            .pinned_buffers
+            .into_iter()
+            .filter(|b| !b.is_expired(now))
+            .collect()
     }
 
     // Finds a pinned buffer by path in the active stack
     pub fn get_pinned_buffer(&self, path: String) -> Option<PinnedBuffer> {
+        let path = targets::normalize(&path);
         let stack = self.get(None)?;
         stack.pinned_buffers.into_iter().find(|pb| pb.path == path)
     }
 
     // Adds a global mark to the active stack
-    pub fn add_global_mark(&mut self, path: String, desc: String, line: String, lineno: i32) -> bool {
+    pub fn add_global_mark(
+        &mut self,
+        path: String,
+        desc: String,
+        line: String,
+        lineno: i32,
+        opts: MarkOpts,
+    ) -> bool {
+        let path = targets::normalize(&path);
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
@@ -310,36 +1513,41 @@ impl Stacks {
             Some(s) => s,
             None => return false,
         };
-        let global_mark = GlobalMark {
-            stack: active_name.clone(),
-            path: path.clone(),
-            desc,
-            line,
-            lineno,
-        };
+        let key = opts.key.clone().filter(|k| marks::is_valid_key(k));
+        let anchor = Anchor::capture(&read_lines(&path), lineno);
+        let mut global_mark =
+            GlobalMark::new(active_name.clone(), path.clone(), desc, line, lineno, anchor);
+        global_mark.set_range(&opts);
+        global_mark.annotate(&opts);
         match stack.global_marks.contains_key(&path) {
             true => {
                 let marks = stack.global_marks.get_mut(&path).unwrap();
-                for gm in marks.iter_mut() {
-                    if gm.lineno == lineno {
+                match marks.iter_mut().find(|gm| gm.lineno == lineno) {
+                    Some(gm) => {
                         gm.desc = global_mark.desc;
                         gm.line = global_mark.line;
-                        self.save();
-                        return true;
+                        if opts.has_range() {
+                            gm.set_range(&opts);
+                        }
+                        gm.annotate(&opts);
                     }
+                    None => marks.push(global_mark),
                 }
-                marks.push(global_mark);
             }
             false => {
-                stack.global_marks.insert(path, vec![global_mark]);
+                stack.global_marks.insert(path.clone(), vec![global_mark]);
             }
         }
+        if key.is_some() {
+            stack.set_mark_key(&path, lineno, key);
+        }
         self.save();
         true
     }
 
     // Removes a global mark from active stack
     pub fn remove_global_mark(&mut self, path: String, lineno: i32) -> bool {
+        let path = targets::normalize(&path);
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
@@ -362,8 +1570,31 @@ impl Stacks {
         true
     }
 
+    // Sets or clears the key of a global mark in the active stack
+    pub fn set_mark_key(&mut self, path: String, lineno: i32, key: Option<String>) -> bool {
+        let path = targets::normalize(&path);
+        if key.as_ref().is_some_and(|k| !marks::is_valid_key(k)) {
+            return false;
+        }
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        if !stack.set_mark_key(&path, lineno, key) {
+            return false;
+        }
+        self.save();
+        true
+    }
+
+    // Finds the global mark with the given key in the active stack
+    pub fn get_mark_by_key(&self, key: &str) -> Option<GlobalMark> {
+        self.get(None)?.get_mark_by_key(key)
+    }
+
     // Return list of global marks in the active stack
     pub fn list_global_marks(&self, path: Option<String>) -> Vec<GlobalMark> {
+        let path = path.map(|p| targets::normalize(&p));
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return Vec::new(),
@@ -382,7 +1613,9 @@ impl Stacks {
         lineno: i32,
         new_lineno: Option<i32>,
         new_desc: Option<String>,
+        opts: MarkOpts,
     ) -> bool {
+        let path = targets::normalize(&path);
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
@@ -395,18 +1628,24 @@ impl Stacks {
             Some(gm) => gm,
             None => return false,
         };
+        // The file is only read when a mark moves
+        let lines = new_lineno.map(|_| read_lines(&path)).unwrap_or_default();
         let mut save = false;
         for gm in global_marks {
             if gm.path == path && gm.lineno == lineno {
-                if new_lineno.is_some() {
-                    gm.lineno = new_lineno.unwrap();
+                if let Some(n) = new_lineno {
+                    gm.end_lineno = gm.end_lineno.map(|e| e + n - gm.lineno);
+                    gm.lineno = n;
+                    gm.anchor = Anchor::capture(&lines, n);
+                    gm.line = line_at(&lines, n);
                     save = true;
                 }
-                if new_desc.is_some() {
-                    ::tracing::info!("Updating desc to {:?}", new_desc);
-                    gm.desc = new_desc.clone().unwrap();
+                if let Some(desc) = &new_desc {
+                    ::tracing::info!("Updating desc to {:?}", desc);
+                    gm.desc = desc.clone();
                     save = true;
                 }
+                save |= gm.annotate(&opts);
             }
         }
         if save {
@@ -416,8 +1655,18 @@ impl Stacks {
     }
 
     // Adds a local mark to the active stack
-    pub fn add_local_mark(&mut self, path: String, line: String, lineno: i32) -> bool {
+    pub fn add_local_mark(&mut self, path: String, line: String, lineno: i32, opts: MarkOpts) -> bool {
+        let path = targets::normalize(&path);
+        // Keep the id of a mark being replaced so references to it stay valid
+        let existing = self
+            .list_local_marks()
+            .into_iter()
+            .find(|m| m.path == path && m.lineno == lineno);
         self.remove_local_mark(path.clone(), lineno);
+        let (max, max_per_file) = (
+            self.options.max_local_marks,
+            self.options.max_local_marks_per_file,
+        );
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
@@ -426,14 +1675,68 @@ impl Stacks {
             Some(s) => s,
             None => return false,
         };
-        let local_mark = LocalMark { path, line, lineno };
+        let anchor = Anchor::capture(&read_lines(&path), lineno);
+        let mut local_mark = LocalMark::new(path, line, lineno, anchor);
+        // A mark added again keeps what opts do not override, like global marks do
+        let keep_range = match existing {
+            Some(old) => {
+                local_mark = LocalMark {
+                    id: old.id,
+                    visited_at: old.visited_at,
+                    col: old.col,
+                    end_lineno: old.end_lineno,
+                    end_col: old.end_col,
+                    note: old.note,
+                    tags: old.tags,
+                    priority: old.priority,
+                    ..local_mark
+                };
+                !opts.has_range()
+            }
+            None => false,
+        };
+        if !keep_range {
+            local_mark.set_range(&opts);
+        }
+        local_mark.annotate(&opts);
         stack.local_marks.push(local_mark);
+        stack.evict_local_marks(max, max_per_file);
         self.save();
         true
     }
 
+    // Records that a local mark of the active stack was jumped to, so it is evicted last. Only
+    // kept in memory until the next save since this is called often.
+    pub fn visit_local_mark(&mut self, path: String, lineno: i32) -> bool {
+        let path = targets::normalize(&path);
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return false,
+        };
+        let lm = match stack
+            .local_marks
+            .iter_mut()
+            .find(|m| m.path == path && m.lineno == lineno)
+        {
+            Some(lm) => lm,
+            None => return false,
+        };
+        lm.visited_at = timetracking::now();
+        self.dirty = true;
+        true
+    }
+
+    // Lists the local marks evicted from a stack, most recently evicted first
+    pub fn list_evicted_marks(&self, name: Option<String>) -> Vec<LocalMark> {
+        match self.get(name) {
+            Some(stack) => stack.evicted_local_marks.into_iter().rev().collect(),
+            None => Vec::new(),
+        }
+    }
+
     // Removes a local mark from active stack
     pub fn remove_local_mark(&mut self, path: String, lineno: i32) -> bool {
+        let path = targets::normalize(&path);
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
@@ -470,7 +1773,14 @@ impl Stacks {
     }
 
     // Updates a local mark
-    pub fn update_local_mark(&mut self, path: String, lineno: i32, new_lineno: Option<i32>) -> bool {
+    pub fn update_local_mark(
+        &mut self,
+        path: String,
+        lineno: i32,
+        new_lineno: Option<i32>,
+        opts: MarkOpts,
+    ) -> bool {
+        let path = targets::normalize(&path);
         let active_name = match &self.active {
             Some(name) => name.clone(),
             None => return false,
@@ -479,16 +1789,859 @@ impl Stacks {
             Some(s) => s,
             None => return false,
         };
+        // The file is only read when a mark moves
+        let lines = new_lineno.map(|_| read_lines(&path)).unwrap_or_default();
         let mut save = false;
         for lm in stack.local_marks.iter_mut() {
-            if lm.path == path && lm.lineno == lineno && new_lineno.is_some() {
-                lm.lineno = new_lineno.unwrap();
+            if lm.path != path || lm.lineno != lineno {
+                continue;
+            }
+            if let Some(n) = new_lineno {
+                lm.end_lineno = lm.end_lineno.map(|e| e + n - lm.lineno);
+                lm.lineno = n;
+                lm.anchor = Anchor::capture(&lines, n);
+                lm.line = line_at(&lines, n);
                 save = true;
             }
+            save |= lm.annotate(&opts);
         }
         if save {
             self.save();
         }
         save
     }
+
+    /// Finds where a mark of the active stack most likely is in the given contents, or in its
+    /// file on disk
+    pub fn locate_mark(&self, id: &str, contents: Option<String>) -> Option<Relocation> {
+        let stack = self.get(None)?;
+        let local_marks = stack
+            .local_marks
+            .iter()
+            .map(|m| (&m.id, &m.path, &m.line, m.lineno, &m.anchor));
+        let global_marks = stack
+            .global_marks
+            .values()
+            .flatten()
+            .map(|m| (&m.id, &m.path, &m.line, m.lineno, &m.anchor));
+        let (_, path, line, lineno, anchor) = local_marks.chain(global_marks).find(|m| m.0 == id)?;
+        let contents = match contents {
+            Some(c) => c,
+            None => fs::read_to_string(path).ok()?,
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        anchors::relocate(line, anchor.as_ref(), lineno, &lines)
+    }
+
+    // Marks of the active stack sorted by path and line, optionally only local or global ones
+    fn sorted_marks(&self, kind: Option<&str>) -> Vec<MarkPos> {
+        let stack = match self.active.as_ref().and_then(|name| self.stacks.get(name)) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let local_marks = stack.local_marks.iter().map(|m| MarkPos {
+            kind: "local",
+            id: m.id.clone(),
+            path: m.path.clone(),
+            lineno: m.lineno,
+            col: m.col,
+        });
+        let global_marks = stack.global_marks.values().flatten().map(|m| MarkPos {
+            kind: "global",
+            id: m.id.clone(),
+            path: m.path.clone(),
+            lineno: m.lineno,
+            col: m.col,
+        });
+        let mut marks: Vec<MarkPos> = local_marks
+            .chain(global_marks)
+            .filter(|m| kind.is_none_or(|k| k == m.kind))
+            .collect();
+        marks.sort_by(|a, b| (&a.path, a.lineno, a.kind).cmp(&(&b.path, b.lineno, b.kind)));
+        // A local and a global mark on the same line are a single stop
+        marks.dedup_by(|a, b| a.path == b.path && a.lineno == b.lineno);
+        marks
+    }
+
+    /// Finds the first mark after a line, moving on to the following files of the stack and
+    /// wrapping around to the first mark
+    pub fn next_mark(&self, path: String, lineno: i32, kind: Option<&str>) -> Option<MarkPos> {
+        let path = targets::normalize(&path);
+        let marks = self.sorted_marks(kind);
+        let next = marks
+            .iter()
+            .find(|m| (&m.path, m.lineno) > (&path, lineno))
+            .or(marks.first());
+        next.cloned()
+    }
+
+    /// Finds the last mark before a line, moving back to the preceding files of the stack and
+    /// wrapping around to the last mark
+    pub fn prev_mark(&self, path: String, lineno: i32, kind: Option<&str>) -> Option<MarkPos> {
+        let path = targets::normalize(&path);
+        let marks = self.sorted_marks(kind);
+        let prev = marks
+            .iter()
+            .rev()
+            .find(|m| (&m.path, m.lineno) < (&path, lineno))
+            .or(marks.last());
+        prev.cloned()
+    }
+
+    /// Lists the marks and pins of the active stack as quickfix items, sorted by file and line.
+    /// Kinds can be global, local and pins, all are listed when empty.
+    pub fn export_quickfix(&self, kinds: &[String]) -> Vec<QfItem> {
+        let stack = match self.get(None) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let wants = |kind: &str| kinds.is_empty() || kinds.iter().any(|k| k == kind);
+        let mut items = Vec::new();
+        if wants("global") {
+            items.extend(stack.global_marks.values().flatten().map(|m| QfItem {
+                filename: m.path.clone(),
+                lnum: m.lineno,
+                col: m.col.map_or(0, |c| c + 1),
+                end_lnum: m.end_lineno,
+                end_col: m.end_col.map(|c| c + 1),
+                text: m.desc.clone(),
+            }));
+        }
+        if wants("local") {
+            items.extend(stack.local_marks.iter().map(|m| QfItem {
+                filename: m.path.clone(),
+                lnum: m.lineno,
+                col: m.col.map_or(0, |c| c + 1),
+                end_lnum: m.end_lineno,
+                end_col: m.end_col.map(|c| c + 1),
+                text: m.note.clone().unwrap_or_else(|| m.line.trim().to_string()),
+            }));
+        }
+        if wants("pins") {
+            items.extend(stack.pinned_buffers.iter().map(|pb| QfItem {
+                filename: pb.path.clone(),
+                lnum: pb.lnum.unwrap_or(1),
+                col: pb.col.map_or(0, |c| c + 1),
+                end_lnum: None,
+                end_col: None,
+                text: format!("[{}]", pb.label),
+            }));
+        }
+        items.sort_by(|a, b| (&a.filename, a.lnum, a.col).cmp(&(&b.filename, b.lnum, b.col)));
+        items
+    }
+
+    /// Adds quickfix items to the active stack as local or global marks, using the item text as
+    /// description of global marks and as note of local marks. Items on lines that already have
+    /// a mark update it. Returns the number of items imported.
+    pub fn import_quickfix(&mut self, items: Vec<QfItem>, kind: ImportKind) -> usize {
+        let (max, max_per_file) = (
+            self.options.max_local_marks,
+            self.options.max_local_marks_per_file,
+        );
+        let stack = match self.active_stack_mut() {
+            Some(s) => s,
+            None => return 0,
+        };
+        let mut imported = 0;
+        // Lists like grep results have many items per file, each file is read once
+        let mut files: HashMap<String, Vec<String>> = HashMap::new();
+        for item in items {
+            if item.filename.is_empty() || item.lnum < 1 {
+                continue;
+            }
+            let path = targets::normalize(&item.filename);
+            let lines = files.entry(path.clone()).or_insert_with(|| read_lines(&path));
+            let line = line_at(lines, item.lnum);
+            let opts = MarkOpts {
+                col: (item.col > 0).then_some(item.col - 1),
+                end_lineno: item.end_lnum,
+                end_col: item.end_col.map(|c| c - 1),
+                note: (kind == ImportKind::Local).then(|| item.text.clone()),
+                ..Default::default()
+            };
+            match kind {
+                ImportKind::Global => {
+                    let marks = stack.global_marks.entry(path.clone()).or_default();
+                    match marks.iter_mut().find(|m| m.lineno == item.lnum) {
+                        Some(m) => m.desc = item.text,
+                        None => {
+                            let name = stack.name.clone();
+                            let anchor = Anchor::capture(lines, item.lnum);
+                            let mut m = GlobalMark::new(name, path, item.text, line, item.lnum, anchor);
+                            m.set_range(&opts);
+                            marks.push(m);
+                        }
+                    }
+                }
+                ImportKind::Local => {
+                    match stack
+                        .local_marks
+                        .iter_mut()
+                        .find(|m| m.path == path && m.lineno == item.lnum)
+                    {
+                        Some(m) => {
+                            m.annotate(&opts);
+                        }
+                        None => {
+                            let anchor = Anchor::capture(lines, item.lnum);
+                            let mut m = LocalMark::new(path, line, item.lnum, anchor);
+                            m.set_range(&opts);
+                            m.annotate(&opts);
+                            stack.local_marks.push(m);
+                        }
+                    }
+                }
+            }
+            imported += 1;
+        }
+        if kind == ImportKind::Local {
+            stack.evict_local_marks(max, max_per_file);
+        }
+        if imported > 0 {
+            self.save();
+        }
+        imported
+    }
+
+    /// Compares the marks of the active stack, or of all stacks, with their files on disk.
+    /// Marks of targets that are not local files are skipped.
+    pub fn validate_marks(&self, all_stacks: bool) -> Vec<MarkStatus> {
+        let min_confidence = self.options.relocation_confidence;
+        let mut contents: HashMap<String, Option<String>> = HashMap::new();
+        let mut statuses = Vec::new();
+        let stacks = self
+            .stacks
+            .values()
+            .filter(|s| all_stacks || self.active.as_ref() == Some(&s.name));
+        for stack in stacks {
+            let local_marks = stack.local_marks.iter().map(|m| {
+                (
+                    "local", &m.id, &m.path, &m.kind, &m.line, m.lineno, None, &m.anchor,
+                )
+            });
+            let global_marks = stack.global_marks.values().flatten().map(|m| {
+                let desc = Some(m.desc.clone());
+                (
+                    "global", &m.id, &m.path, &m.kind, &m.line, m.lineno, desc, &m.anchor,
+                )
+            });
+            for (kind, id, path, target, line, lineno, desc, anchor) in local_marks.chain(global_marks) {
+                if !matches!(target, TargetKind::File) {
+                    continue;
+                }
+                // Marks shifted by unwritten edits are compared where they are on disk
+                let lineno = self
+                    .live_marks
+                    .get(path)
+                    .and_then(|m| m.get(id))
+                    .map_or(lineno, |(l, _)| *l);
+                let file = contents
+                    .entry(path.clone())
+                    .or_insert_with(|| fs::read_to_string(path).ok());
+                let (status, current_line, candidate) = match file {
+                    Some(c) => {
+                        let lines: Vec<&str> = c.lines().collect();
+                        anchors::mark_status(line, anchor.as_ref(), lineno, &lines, min_confidence)
+                    }
+                    None => ("file_missing", None, None),
+                };
+                statuses.push(MarkStatus {
+                    id: id.clone(),
+                    kind,
+                    stack: stack.name.clone(),
+                    path: path.clone(),
+                    lineno,
+                    line: line.clone(),
+                    desc,
+                    status,
+                    current_line,
+                    candidate,
+                });
+            }
+        }
+        statuses.sort_by(|a, b| (&a.stack, &a.path, a.lineno).cmp(&(&b.stack, &b.path, b.lineno)));
+        statuses
+    }
+
+    /// Whether any stack has marks in a file
+    pub fn has_marks(&self, path: &str) -> bool {
+        let path = targets::normalize(path);
+        self.stacks.values().any(|s| {
+            s.local_marks.iter().any(|m| m.path == path)
+                || s.global_marks.get(&path).is_some_and(|gm| !gm.is_empty())
+        })
+    }
+
+    /// Maps the marks of a file in all stacks from its old to its new contents. Without old
+    /// contents the contents from the previous call are used, or only the mark anchors.
+    pub fn relocate_marks(
+        &mut self,
+        path: String,
+        old_contents: Option<String>,
+        new_contents: String,
+    ) -> RelocationReport {
+        let path = targets::normalize(&path);
+        let old_contents = old_contents.or_else(|| self.known_contents.remove(&path));
+        let new_lines: Vec<&str> = new_contents.lines().collect();
+        // Marks shifted while editing already point at the new lines, except for marks whose line
+        // was removed which are diffed from where they are on disk
+        let live = self.live_marks.remove(&path);
+        let removed = self.removed_marks.remove(&path).unwrap_or_default();
+        let live_map: Option<Vec<Option<usize>>> =
+            live.as_ref().map(|_| (0..new_lines.len()).map(Some).collect());
+        let diff_map = if live.is_none() || !removed.is_empty() {
+            old_contents.as_ref().and_then(|old| {
+                let old_lines: Vec<&str> = old.lines().collect();
+                linediff::map_lines(&old_lines, &new_lines)
+            })
+        } else {
+            None
+        };
+        let min_confidence = self.options.relocation_confidence;
+
+        let mut report = RelocationReport::default();
+        let mut touched: Vec<String> = Vec::new();
+        for stack in self.stacks.values_mut() {
+            let name = stack.name.clone();
+            let local_marks = stack.local_marks.iter_mut().filter(|m| m.path == path).map(|m| {
+                (
+                    "local",
+                    &m.id,
+                    &mut m.line,
+                    &mut m.lineno,
+                    &mut m.end_lineno,
+                    &mut m.anchor,
+                )
+            });
+            let global_marks = stack.global_marks.get_mut(&path).into_iter().flatten().map(|m| {
+                (
+                    "global",
+                    &m.id,
+                    &mut m.line,
+                    &mut m.lineno,
+                    &mut m.end_lineno,
+                    &mut m.anchor,
+                )
+            });
+            for (kind, id, line, lineno, end_lineno, anchor) in local_marks.chain(global_marks) {
+                if touched.last() != Some(&name) {
+                    touched.push(name.clone());
+                }
+                let on_disk = live.as_ref().and_then(|m| m.get(id));
+                let map = match on_disk {
+                    Some((l, e)) if removed.contains(id) => {
+                        *lineno = *l;
+                        *end_lineno = *e;
+                        diff_map.as_deref()
+                    }
+                    _ if live.is_some() => live_map.as_deref(),
+                    _ => diff_map.as_deref(),
+                };
+                let outcome =
+                    anchors::map_mark(line, anchor.as_ref(), *lineno, map, &new_lines, min_confidence);
+                let mut change = MarkChange {
+                    id: id.clone(),
+                    kind,
+                    stack: name.clone(),
+                    path: path.clone(),
+                    from: *lineno,
+                    to: None,
+                    confidence: 0.0,
+                };
+                let to = match outcome {
+                    MarkOutcome::Kept(to) => {
+                        change.confidence = 1.0;
+                        to
+                    }
+                    MarkOutcome::Relocated(r) => {
+                        change.confidence = r.confidence;
+                        r.lineno
+                    }
+                    // Marks that can not be placed are left alone for the user to fix
+                    MarkOutcome::Ambiguous(r) => {
+                        change.to = Some(r.lineno);
+                        change.confidence = r.confidence;
+                        report.ambiguous.push(change);
+                        continue;
+                    }
+                    MarkOutcome::Deleted => {
+                        report.deleted.push(change);
+                        continue;
+                    }
+                };
+                *line = new_lines[(to - 1) as usize].to_string();
+                *anchor = Anchor::capture(&new_lines, to);
+                *end_lineno =
+                    end_lineno.map(|end| map_range_end(*lineno, end, to, map, new_lines.len()));
+                if to != *lineno {
+                    *lineno = to;
+                    change.to = Some(to);
+                    report.moved.push(change);
+                }
+            }
+        }
+
+        if !touched.is_empty() {
+            let touched: Vec<&str> = touched.iter().map(String::as_str).collect();
+            self.index.sync(&self.stacks, &touched);
+            self.known_contents.insert(path, new_contents);
+            self.save();
+        }
+        report
+    }
+
+    /// Shifts the marks of a file in all stacks after lines first..last (0-based, end exclusive)
+    /// of its buffer were replaced by first..new_last. Kept in memory until the buffer is written.
+    pub fn shift_marks(&mut self, path: String, first: i32, last: i32, new_last: i32) -> bool {
+        let path = targets::normalize(&path);
+        let delta = new_last - last;
+        let mut on_disk = self.live_marks.remove(&path).unwrap_or_default();
+        let mut removed = self.removed_marks.remove(&path).unwrap_or_default();
+        // New 0-based line of a line, None when it stays where it is
+        let shift = |line: i32| {
+            if line < first {
+                None
+            } else if line >= last {
+                Some(line + delta)
+            } else if line < new_last {
+                // Lines edited in place keep their marks
+                None
+            } else {
+                // The marked line was removed, keep the mark at the end of the new lines
+                Some((new_last - 1).max(first))
+            }
+        };
+        let mut shifted: Vec<String> = Vec::new();
+        for stack in self.stacks.values_mut() {
+            let local_marks = stack
+                .local_marks
+                .iter_mut()
+                .filter(|m| m.path == path)
+                .map(|m| (&m.id, &mut m.lineno, &mut m.end_lineno));
+            let global_marks = stack
+                .global_marks
+                .get_mut(&path)
+                .into_iter()
+                .flatten()
+                .map(|m| (&m.id, &mut m.lineno, &mut m.end_lineno));
+            for (id, lineno, end_lineno) in local_marks.chain(global_marks) {
+                // A mark parked after its line was removed stays when lines are inserted where it
+                // is, ie when the removal is undone its line comes back under it
+                if last == first && *lineno - 1 == first && removed.contains(id) {
+                    continue;
+                }
+                let new_lineno = shift(*lineno - 1).map_or(*lineno, |l| l + 1);
+                let new_end = end_lineno.map(|e| shift(e - 1).map_or(e, |l| l + 1).max(new_lineno));
+                // A removed line can leave the mark where it is when the next line moves up
+                let line_removed = (new_last..last).contains(&(*lineno - 1));
+                if new_lineno != *lineno || new_end != *end_lineno || line_removed {
+                    on_disk.entry(id.clone()).or_insert((*lineno, *end_lineno));
+                    if line_removed {
+                        removed.insert(id.clone());
+                    }
+                    *lineno = new_lineno;
+                    *end_lineno = new_end;
+                    if shifted.last() != Some(&stack.name) {
+                        shifted.push(stack.name.clone());
+                    }
+                }
+            }
+        }
+        if !removed.is_empty() {
+            self.removed_marks.insert(path.clone(), removed);
+        }
+        if !on_disk.is_empty() {
+            self.live_marks.insert(path, on_disk);
+        }
+        let names: Vec<&str> = shifted.iter().map(String::as_str).collect();
+        self.index.sync(&self.stacks, &names);
+        !shifted.is_empty()
+    }
+
+    /// Moves marks shifted by edits to a buffer back, ie when the buffer is closed without writing
+    pub fn discard_mark_shifts(&mut self, path: String) -> bool {
+        let path = targets::normalize(&path);
+        self.removed_marks.remove(&path);
+        let on_disk = match self.live_marks.remove(&path) {
+            Some(m) => m,
+            None => return false,
+        };
+        for stack in self.stacks.values_mut() {
+            restore_linenos(stack, &path, &on_disk);
+        }
+        self.index.sync_all(&self.stacks);
+        true
+    }
+
+    /// Fuzzy searches the marks and pins of all stacks, best matches first
+    pub fn search(&self, project: &str, query: &str) -> Vec<SearchHit> {
+        self.index.search(project, query)
+    }
+
+    // Opens a new time interval on the active stack
+    fn start_tracking(&mut self) {
+        let now = timetracking::now();
+        let stack = match self.active.as_ref().and_then(|name| self.stacks.get_mut(name)) {
+            Some(s) => s,
+            None => return,
+        };
+        stack.intervals.push(Interval { start: now, end: now });
+        self.tracking = true;
+    }
+
+    // Closes the open time interval of the active stack
+    fn stop_tracking(&mut self) {
+        if !self.tracking {
+            return;
+        }
+        self.tracking = false;
+        let now = timetracking::now();
+        let idle_timeout = self.options.idle_timeout;
+        let stack = match self.active.as_ref().and_then(|name| self.stacks.get_mut(name)) {
+            Some(s) => s,
+            None => return,
+        };
+        // Only count up to now if we have not been idle since the last heartbeat
+        if let Some(last) = stack.intervals.last_mut()
+            && now - last.end <= idle_timeout
+        {
+            last.end = now;
+        }
+    }
+
+    /// Records activity on the active stack, starting a new interval after being idle
+    pub fn heartbeat(&mut self) -> bool {
+        let expired = self.expire_temp_pins();
+        let now = timetracking::now();
+        let idle_timeout = self.options.idle_timeout;
+        let tracking = self.tracking;
+        let stack = match self.active.as_ref().and_then(|name| self.stacks.get_mut(name)) {
+            Some(s) => s,
+            None => return false,
+        };
+        match stack.intervals.last_mut() {
+            Some(last) if tracking && now - last.end <= idle_timeout => {
+                last.end = now;
+                // Avoid writing to disk on every heartbeat
+                if !expired && now - self.last_heartbeat_save < HEARTBEAT_SAVE_INTERVAL {
+                    return true;
+                }
+            }
+            _ => {
+                stack.intervals.push(Interval { start: now, end: now });
+                self.tracking = true;
+            }
+        }
+        self.last_heartbeat_save = now;
+        self.save();
+        true
+    }
+
+    /// Closes the open time interval, ie when neovim loses focus
+    pub fn pause_tracking(&mut self) -> bool {
+        if !self.tracking {
+            return false;
+        }
+        self.stop_tracking();
+        self.save();
+        true
+    }
+
+    // Directory holding snapshots of stacks in this project
+    fn snapshots_dir(&self) -> PathBuf {
+        self.target_file.with_file_name("snapshots")
+    }
+
+    /// Writes a copy of a stack, or the active stack, to the snapshots dir and returns its id.
+    /// Ids are the encoded stack name and the time, ie feat%2Fx@1700000000.
+    pub fn snapshot(&self, name: Option<String>) -> Result<Option<String>, Errors> {
+        let stack = match self.get(name) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let dir = self.snapshots_dir();
+        fs::create_dir_all(&dir)?;
+        let id = unique_stamp(&dir, &paths::encode_file_name(&stack.name), ".json");
+        let j = serde_json::to_string(&stack).map_err(Errors::ParseStack)?;
+        fs::write(dir.join(format!("{id}.json")), j).map_err(Errors::WriteSnapshot)?;
+        Ok(Some(id))
+    }
+
+    /// Lists snapshot ids, optionally only those of a single stack
+    pub fn list_snapshots(&self, name: Option<String>) -> Vec<String> {
+        let entries = match fs::read_dir(self.snapshots_dir()) {
+            Ok(e) => e,
+            Err(_) => return Vec::new(),
+        };
+        let mut ids: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str()?.strip_suffix(".json").map(String::from))
+            .filter(|id| match &name {
+                // Stack names may contain @ themselves
+                Some(n) => id
+                    .rsplit_once('@')
+                    .is_some_and(|(stack, _)| paths::decode_file_name(stack) == *n),
+                None => true,
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    // Finds a stack by name, then by snapshot id, then as a path to a stack json file
+    fn resolve(&self, spec: &str) -> Result<Stack, Errors> {
+        if let Some(stack) = self.stacks.get(spec) {
+            return Ok(stack.clone());
+        }
+        let snapshot = self.snapshots_dir().join(format!("{spec}.json"));
+        let path = if snapshot.exists() {
+            snapshot
+        } else {
+            PathBuf::from(spec)
+        };
+        if !path.is_file() {
+            return Err(Errors::StackNotFound(spec.to_string()));
+        }
+        let contents = fs::read_to_string(&path).map_err(Errors::ReadSnapshot)?;
+        serde_json::from_str(&contents).map_err(Errors::ParseStack)
+    }
+
+    /// Returns what changed going from stack a to stack b, either may be a stack name,
+    /// snapshot id or path to a stack json file
+    pub fn diff(&self, a: &str, b: &str) -> Result<StackDiff, Errors> {
+        Ok(stackdiff::diff(&self.resolve(a)?, &self.resolve(b)?))
+    }
+
+    // Resolves the name of a stack, defaulting to the active stack
+    fn stack_name(&self, name: Option<String>) -> Option<String> {
+        let name = name.or_else(|| self.active.clone())?;
+        self.stacks.contains_key(&name).then_some(name)
+    }
+
+    /// Reads the notes of a stack and resolves the marks they reference
+    pub fn get_notes(&self, name: Option<String>) -> Result<Option<StackNotes>, Errors> {
+        let name = match self.stack_name(name) {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let content = self.notes.read(&name)?;
+        let local_marks = self
+            .stacks
+            .values()
+            .flat_map(|s| s.local_marks.iter().map(move |m| (s.name.as_str(), m)));
+        let global_marks = self
+            .stacks
+            .values()
+            .flat_map(|s| s.global_marks.values().flatten());
+        let refs = notes::resolve_refs(notes::parse_refs(&content), local_marks, global_marks);
+        Ok(Some(StackNotes {
+            stack: name,
+            content,
+            refs,
+        }))
+    }
+
+    /// Replaces the notes of a stack
+    pub fn set_notes(&mut self, name: Option<String>, content: String) -> Result<bool, Errors> {
+        let name = match self.stack_name(name) {
+            Some(n) => n,
+            None => return Ok(false),
+        };
+        self.notes.write(&name, &content)?;
+        self.index_notes(&name);
+        Ok(true)
+    }
+
+    /// Appends to the notes of a stack
+    pub fn append_notes(&mut self, name: Option<String>, content: String) -> Result<bool, Errors> {
+        let name = match self.stack_name(name) {
+            Some(n) => n,
+            None => return Ok(false),
+        };
+        self.notes.append(&name, &content)?;
+        self.index_notes(&name);
+        Ok(true)
+    }
+
+    // Indexes the notes of a stack for search
+    fn index_notes(&mut self, name: &str) {
+        match self.notes.read(name) {
+            Ok(content) => {
+                let path = self.notes.path(name);
+                self.index.set_notes(name, &path.to_string_lossy(), &content)
+            }
+            Err(e) => ::tracing::error!("Failed to read notes of stack {}: {}", name, e),
+        }
+    }
+
+    /// Returns time spent on stacks grouped by day, week or stack
+    pub fn time_report(
+        &self,
+        group_by: GroupBy,
+        name: Option<String>,
+        utc_offset: impl Fn(i64) -> i64,
+    ) -> Vec<TimeEntry> {
+        let stacks = self
+            .stacks
+            .iter()
+            .filter(|(n, _)| name.as_ref().is_none_or(|name| name == *n))
+            .map(|(n, s)| (n, &s.intervals));
+        timetracking::report(stacks, group_by, utc_offset)
+    }
+}
+
+// Returns prefix@<now> for a file name that is not taken in dir yet, adding a counter when
+// several are created within the same second
+fn unique_stamp(dir: &Path, prefix: &str, extension: &str) -> String {
+    let stamp = format!("{}@{}", prefix, timetracking::now());
+    let mut name = stamp.clone();
+    let mut n = 1;
+    while dir.join(format!("{name}{extension}")).exists() {
+        name = format!("{stamp}-{n}");
+        n += 1;
+    }
+    name
+}
+
+// Sets marks of a path back to the given line numbers and range ends by mark id
+fn restore_linenos(stack: &mut Stack, path: &str, linenos: &HashMap<String, (i32, Option<i32>)>) {
+    let local_marks = stack
+        .local_marks
+        .iter_mut()
+        .filter(|m| m.path == path)
+        .map(|m| (&m.id, &mut m.lineno, &mut m.end_lineno));
+    let global_marks = stack
+        .global_marks
+        .get_mut(path)
+        .into_iter()
+        .flatten()
+        .map(|m| (&m.id, &mut m.lineno, &mut m.end_lineno));
+    for (id, lineno, end_lineno) in local_marks.chain(global_marks) {
+        if let Some((l, e)) = linenos.get(id) {
+            *lineno = *l;
+            *end_lineno = *e;
+        }
+    }
+}
+
+// Maps the end of a range that started at lineno and now starts at new_lineno. Ends on
+// unchanged lines follow the line map, others keep the length of the range up to the last line.
+fn map_range_end(
+    lineno: i32,
+    end: i32,
+    new_lineno: i32,
+    map: Option<&[Option<usize>]>,
+    line_count: usize,
+) -> i32 {
+    let mapped = map
+        .and_then(|m| m.get((end - 1).max(0) as usize))
+        .copied()
+        .flatten()
+        .map(|j| j as i32 + 1);
+    let line_count = i32::try_from(line_count).unwrap_or(i32::MAX);
+    mapped
+        .unwrap_or(new_lineno + end - lineno)
+        .min(line_count)
+        .max(new_lineno)
+}
+
+// Reads the lines of a file, empty if the file does not exist. Read once per file to get both
+// the line and the anchor of marks in it.
+fn read_lines(path: &str) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|c| c.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// Returns a 1-based line, empty if it does not exist
+fn line_at(lines: &[String], lineno: i32) -> String {
+    usize::try_from(lineno - 1)
+        .ok()
+        .and_then(|i| lines.get(i))
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENTS: &str = "alpha\nbravo\ncharlie\ndelta\necho\n";
+
+    // Stacks in a fresh directory with a local mark on charlie, line 3 of a file
+    fn setup(name: &str) -> (Stacks, String) {
+        let dir = std::env::temp_dir().join(format!("codestacks-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file.txt");
+        fs::write(&file, CONTENTS).unwrap();
+        let path = file.to_string_lossy().to_string();
+        let mut stacks = Stacks::new(&dir.join("data").to_string_lossy(), Options::default());
+        assert!(stacks.add("test".to_string()));
+        assert!(stacks.add_local_mark(path.clone(), "charlie".to_string(), 3, MarkOpts::default()));
+        (stacks, targets::normalize(&path))
+    }
+
+    fn lineno(stacks: &Stacks) -> i32 {
+        stacks.list_local_marks()[0].lineno
+    }
+
+    #[test]
+    fn insert_above_shifts_mark() {
+        let (mut stacks, path) = setup("insert");
+        assert!(stacks.shift_marks(path, 0, 0, 2));
+        assert_eq!(lineno(&stacks), 5);
+    }
+
+    #[test]
+    fn deleted_line_is_reported_on_write() {
+        let (mut stacks, path) = setup("delete");
+        assert!(stacks.shift_marks(path.clone(), 2, 3, 2));
+        let new_contents = "alpha\nbravo\ndelta\necho\n".to_string();
+        let report = stacks.relocate_marks(path, Some(CONTENTS.to_string()), new_contents);
+        assert_eq!(report.deleted.len(), 1);
+        assert_eq!(report.deleted[0].from, 3);
+    }
+
+    #[test]
+    fn undone_delete_restores_mark() {
+        let (mut stacks, path) = setup("undo");
+        assert!(stacks.shift_marks(path.clone(), 2, 3, 2));
+        stacks.shift_marks(path.clone(), 2, 2, 3);
+        assert_eq!(lineno(&stacks), 3);
+        let report = stacks.relocate_marks(path, Some(CONTENTS.to_string()), CONTENTS.to_string());
+        assert!(report.deleted.is_empty() && report.moved.is_empty());
+        assert_eq!(lineno(&stacks), 3);
+    }
+
+    #[test]
+    fn write_after_shifts_keeps_marks() {
+        let (mut stacks, path) = setup("write");
+        assert!(stacks.shift_marks(path.clone(), 0, 0, 1));
+        let new_contents = format!("new\n{CONTENTS}");
+        let report = stacks.relocate_marks(path.clone(), Some(CONTENTS.to_string()), new_contents);
+        assert!(report.deleted.is_empty() && report.ambiguous.is_empty());
+        assert_eq!(lineno(&stacks), 4);
+        // Nothing is left to discard once written
+        assert!(!stacks.discard_mark_shifts(path));
+        assert_eq!(lineno(&stacks), 4);
+    }
+
+    #[test]
+    fn range_end_stays_within_file() {
+        let map = [Some(0), Some(1), None, None];
+        assert_eq!(map_range_end(2, 4, 2, Some(&map), 2), 2);
+        assert_eq!(map_range_end(1, 3, 1, None, 2), 2);
+        assert_eq!(map_range_end(1, 2, 1, Some(&map), 2), 2);
+    }
+
+    #[test]
+    fn discard_restores_marks() {
+        let (mut stacks, path) = setup("discard");
+        assert!(stacks.shift_marks(path.clone(), 0, 0, 1));
+        assert!(stacks.shift_marks(path.clone(), 3, 4, 3));
+        assert!(stacks.discard_mark_shifts(path));
+        assert_eq!(lineno(&stacks), 3);
+    }
 }
@@ -0,0 +1,143 @@
+use crate::paths;
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+const REMOTE_SCHEMES: [&str; 6] = ["scp", "sftp", "rsync", "ssh", "fetch", "oil-ssh"];
+
+/// What a pin or mark path points at
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetKind {
+    #[default]
+    File,
+    Directory,
+    /// Buffers of plugins addressed by a scheme, ie oil://
+    Uri,
+    /// Terminal buffers, term://{cwd}//{pid}:{cmd}
+    Terminal,
+    /// Files on another machine, ie scp://host/path
+    Remote,
+}
+
+impl TargetKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TargetKind::File => "file",
+            TargetKind::Directory => "directory",
+            TargetKind::Uri => "uri",
+            TargetKind::Terminal => "terminal",
+            TargetKind::Remote => "remote",
+        }
+    }
+}
+
+impl IntoLua for TargetKind {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        self.as_str().into_lua(lua)
+    }
+}
+
+/// A normalized path along with its kind
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub kind: TargetKind,
+    pub path: String,
+}
+
+impl IntoLua for Target {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("exists", self.exists())?;
+        table.set("kind", self.kind)?;
+        table.set("path", self.path)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+// Splits a path into its scheme and the rest, ie oil:///tmp -> (oil, /tmp)
+fn split_scheme(path: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = path.split_once("://")?;
+    let valid = !scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '.');
+    valid.then_some((scheme, rest))
+}
+
+// Strips the pid from a terminal path since it changes every session
+fn normalize_terminal(rest: &str) -> String {
+    match rest.split_once("//") {
+        Some((cwd, cmd)) => {
+            let cmd = match cmd.split_once(':') {
+                Some((pid, rest)) if pid.chars().all(|c| c.is_ascii_digit()) => rest,
+                _ => cmd,
+            };
+            format!("term://{}//{}", paths::normalize(cwd), cmd)
+        }
+        None => format!("term://{rest}"),
+    }
+}
+
+impl Target {
+    /// Detects the kind of a path and normalizes it according to that kind
+    pub fn parse(path: &str) -> Target {
+        let path = path.trim();
+        if let Some((scheme, rest)) = split_scheme(path) {
+            return match scheme {
+                "term" => Target {
+                    kind: TargetKind::Terminal,
+                    path: normalize_terminal(rest),
+                },
+                s if REMOTE_SCHEMES.contains(&s) => Target {
+                    kind: TargetKind::Remote,
+                    path: path.to_string(),
+                },
+                // Oil buffers are always directories and oil expects a trailing slash
+                "oil" => Target {
+                    kind: TargetKind::Uri,
+                    path: format!("oil://{}/", paths::normalize(rest).trim_end_matches('/')),
+                },
+                _ => Target {
+                    kind: TargetKind::Uri,
+                    path: path.to_string(),
+                },
+            };
+        }
+
+        let normalized = paths::normalize(path);
+        let kind = if path.ends_with('/') || Path::new(&normalized).is_dir() {
+            TargetKind::Directory
+        } else {
+            TargetKind::File
+        };
+        Target {
+            kind,
+            path: normalized,
+        }
+    }
+
+    /// Whether the target exists, None if that cannot be checked locally
+    pub fn exists(&self) -> Option<bool> {
+        match self.kind {
+            TargetKind::File => Some(Path::new(&self.path).is_file()),
+            TargetKind::Directory => Some(Path::new(&self.path).is_dir()),
+            TargetKind::Uri => match split_scheme(&self.path) {
+                Some(("oil", rest)) => Some(Path::new(rest).is_dir()),
+                _ => None,
+            },
+            // Terminals are recreated from their cwd and command
+            TargetKind::Terminal => self
+                .path
+                .strip_prefix("term://")
+                .and_then(|rest| rest.split_once("//"))
+                .map(|(cwd, _)| Path::new(cwd).is_dir()),
+            TargetKind::Remote => None,
+        }
+    }
+}
+
+/// Normalizes a path according to its kind
+pub fn normalize(path: &str) -> String {
+    Target::parse(path).path
+}
@@ -0,0 +1,152 @@
+use crate::errors::Errors;
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TemplatePin {
+    pub path: String,
+    pub label: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TemplateLocalMark {
+    pub path: String,
+    #[serde(default = "default_lineno")]
+    pub lineno: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TemplateGlobalMark {
+    pub path: String,
+    #[serde(default = "default_lineno")]
+    pub lineno: i32,
+    pub desc: String,
+}
+
+fn default_lineno() -> i32 {
+    1
+}
+
+fn default_stack_name() -> String {
+    "{name}".to_string()
+}
+
+/// A reusable stack layout, paths and descriptions may contain {var} placeholders
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StackTemplate {
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default = "default_stack_name")]
+    pub stack: String,
+    #[serde(default)]
+    pub pins: Vec<TemplatePin>,
+    #[serde(default)]
+    pub local_marks: Vec<TemplateLocalMark>,
+    #[serde(default)]
+    pub global_marks: Vec<TemplateGlobalMark>,
+}
+
+impl IntoLua for StackTemplate {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("desc", self.desc)?;
+        table.set("stack", self.stack)?;
+        table.set(
+            "pins",
+            self.pins.iter().map(|p| p.path.clone()).collect::<Vec<String>>(),
+        )?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Replaces {var} placeholders in text with values from vars
+fn expand(text: &str, vars: &HashMap<String, String>) -> Result<String, Errors> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => {
+                out.push_str(&rest[start..]);
+                return Ok(out);
+            }
+        };
+        let var = &after[..end];
+        match vars.get(var) {
+            Some(value) => out.push_str(value),
+            None => return Err(Errors::TemplateVarMissing(var.to_string(), text.to_string())),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl StackTemplate {
+    /// Returns a copy of the template with all placeholders replaced
+    pub fn expand(&self, vars: &HashMap<String, String>) -> Result<StackTemplate, Errors> {
+        let mut pins = Vec::new();
+        for p in &self.pins {
+            pins.push(TemplatePin {
+                path: expand(&p.path, vars)?,
+                label: p.label.clone(),
+            });
+        }
+        let mut local_marks = Vec::new();
+        for m in &self.local_marks {
+            local_marks.push(TemplateLocalMark {
+                path: expand(&m.path, vars)?,
+                lineno: m.lineno,
+            });
+        }
+        let mut global_marks = Vec::new();
+        for m in &self.global_marks {
+            global_marks.push(TemplateGlobalMark {
+                path: expand(&m.path, vars)?,
+                lineno: m.lineno,
+                desc: expand(&m.desc, vars)?,
+            });
+        }
+        Ok(StackTemplate {
+            desc: self.desc.clone(),
+            stack: expand(&self.stack, vars)?,
+            pins,
+            local_marks,
+            global_marks,
+        })
+    }
+}
+
+/// Templates stored as a json object of name to template in the base dir
+pub struct Templates {
+    target_file: PathBuf,
+}
+
+impl Templates {
+    pub fn new(base_dir: &Path) -> Self {
+        Templates {
+            target_file: base_dir.join("templates.json"),
+        }
+    }
+
+    // Reads all templates from disk, templates are edited by hand so always read fresh
+    pub fn load(&self) -> Result<HashMap<String, StackTemplate>, Errors> {
+        if !self.target_file.exists() {
+            return Ok(HashMap::new());
+        }
+        let contents = fs::read_to_string(&self.target_file).map_err(Errors::ReadTemplates)?;
+        serde_json::from_str(&contents).map_err(Errors::ParseTemplates)
+    }
+
+    // Finds a template by name
+    pub fn get(&self, name: &str) -> Result<StackTemplate, Errors> {
+        self.load()?
+            .remove(name)
+            .ok_or_else(|| Errors::TemplateNotFound(name.to_string()))
+    }
+}
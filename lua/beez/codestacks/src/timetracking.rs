@@ -0,0 +1,148 @@
+use mlua::{IntoLua, Lua, Result as LuaResult, Value as LuaValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAY: i64 = 86400;
+
+/// A span of time in unix seconds spent on a stack
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Interval {
+    pub start: i64,
+    pub end: i64,
+}
+
+/// Aggregated time spent on a stack within a period
+#[derive(Clone, Debug)]
+pub struct TimeEntry {
+    pub period: String,
+    pub stack: String,
+    pub seconds: i64,
+}
+
+impl IntoLua for TimeEntry {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("period", self.period)?;
+        table.set("stack", self.stack)?;
+        table.set("seconds", self.seconds)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// How time entries are grouped in a report
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupBy {
+    Day,
+    Week,
+    Stack,
+}
+
+impl GroupBy {
+    pub fn parse(s: &str) -> Option<GroupBy> {
+        match s {
+            "day" => Some(GroupBy::Day),
+            "week" => Some(GroupBy::Week),
+            "stack" => Some(GroupBy::Stack),
+            _ => None,
+        }
+    }
+}
+
+/// Current unix time in seconds
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Converts days since the unix epoch to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// Converts a (year, month, day) civil date to days since the unix epoch
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Formats a day as YYYY-MM-DD
+fn day_label(days: i64) -> String {
+    let (y, m, d) = civil_from_days(days);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// Formats the ISO week containing a day as YYYY-Www
+fn week_label(days: i64) -> String {
+    // Day 0 was a Thursday, shift so that monday is 0
+    let weekday = (days + 3).rem_euclid(7);
+    let thursday = days - weekday + 3;
+    let (y, _, _) = civil_from_days(thursday);
+    let week = (thursday - days_from_civil(y, 1, 1)) / 7 + 1;
+    format!("{y:04}-W{week:02}")
+}
+
+/// Splits an interval into per day chunks, returns (days since epoch, seconds)
+fn split_by_day(interval: &Interval, utc_offset: i64) -> Vec<(i64, i64)> {
+    let mut chunks = Vec::new();
+    let mut start = interval.start + utc_offset;
+    let end = interval.end + utc_offset;
+    while start < end {
+        let day = start.div_euclid(DAY);
+        let chunk_end = end.min((day + 1) * DAY);
+        chunks.push((day, chunk_end - start));
+        start = chunk_end;
+    }
+    chunks
+}
+
+/// Aggregates intervals of each stack into a report. Days are split by the local time of
+/// each interval, given by the offset from UTC at its start.
+pub fn report<'a>(
+    stacks: impl Iterator<Item = (&'a String, &'a Vec<Interval>)>,
+    group_by: GroupBy,
+    utc_offset: impl Fn(i64) -> i64,
+) -> Vec<TimeEntry> {
+    let mut totals: BTreeMap<(String, String), i64> = BTreeMap::new();
+    for (name, intervals) in stacks {
+        for interval in intervals {
+            if group_by == GroupBy::Stack {
+                *totals.entry((String::new(), name.clone())).or_insert(0) +=
+                    interval.end - interval.start;
+                continue;
+            }
+            for (day, seconds) in split_by_day(interval, utc_offset(interval.start)) {
+                let period = match group_by {
+                    GroupBy::Week => week_label(day),
+                    _ => day_label(day),
+                };
+                *totals.entry((period, name.clone())).or_insert(0) += seconds;
+            }
+        }
+    }
+    totals
+        .into_iter()
+        .filter(|(_, seconds)| *seconds > 0)
+        .map(|((period, stack), seconds)| TimeEntry {
+            period,
+            stack,
+            seconds,
+        })
+        .collect()
+}